@@ -0,0 +1,111 @@
+use std::{collections::HashSet, fs, io::Write, path::{Path, PathBuf}, sync::{Arc, Mutex}};
+
+use anyhow::{Context, Result};
+use druid::Data;
+use tokio::sync::oneshot;
+
+use crate::server::ClientInfo;
+
+/// A decision on whether a new client may connect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Approval {
+    /// Accepts the client for this connection.
+    AcceptOnce,
+    /// Accepts the client and adds it to the allowlist.
+    AcceptAlways,
+    /// Rejects the client.
+    Deny,
+}
+
+/// A request to approve a new client, which can be answered once.
+#[derive(Debug, Clone, Data)]
+pub struct ApprovalRequest {
+    pub client: ClientInfo,
+    reply: Arc<Mutex<Option<oneshot::Sender<Approval>>>>,
+}
+
+impl ApprovalRequest {
+    pub fn new(client: ClientInfo) -> (Self, oneshot::Receiver<Approval>) {
+        let (tx, rx) = oneshot::channel();
+        (Self { client, reply: Arc::new(Mutex::new(Some(tx))) }, rx)
+    }
+
+    /// Answers the request. Subsequent answers are ignored.
+    pub fn answer(&self, approval: Approval) {
+        if let Some(reply) = self.reply.lock().unwrap().take() {
+            // The client may have given up waiting already
+            let _ = reply.send(approval);
+        }
+    }
+}
+
+/// The hosts (IP addresses) whose clients are accepted without asking,
+/// optionally persisted to a file with one address per line.
+pub struct Allowlist {
+    path: Option<PathBuf>,
+    hosts: Mutex<HashSet<String>>,
+}
+
+impl Allowlist {
+    /// Loads the allowlist at the given path, if any. Empty lines
+    /// and lines starting with `#` are ignored.
+    pub fn load(path: Option<PathBuf>) -> Result<Self> {
+        let hosts = match &path {
+            Some(path) if path.exists() => fs::read_to_string(path)
+                .with_context(|| format!("Could not read allowlist from {}", path.display()))?
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(str::to_owned)
+                .collect(),
+            _ => HashSet::new(),
+        };
+        Ok(Self { path, hosts: Mutex::new(hosts) })
+    }
+
+    pub fn contains(&self, host: &str) -> bool {
+        self.hosts.lock().unwrap().contains(host)
+    }
+
+    /// Adds the given host, appending it to the file.
+    pub fn add(&self, host: &str) -> Result<()> {
+        if self.hosts.lock().unwrap().insert(host.to_owned()) {
+            if let Some(path) = &self.path {
+                append_line(path, host).with_context(|| format!("Could not write allowlist to {}", path.display()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn append_line(path: &Path, line: &str) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", line)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use super::Allowlist;
+
+    #[test]
+    fn persists_added_hosts() {
+        let path = env::temp_dir().join(format!("robo-allowlist-{}", process::id()));
+        fs::write(&path, "# Trusted hosts\n192.168.0.2\n").unwrap();
+
+        let allowlist = Allowlist::load(Some(path.clone())).unwrap();
+        assert!(allowlist.contains("192.168.0.2"));
+        assert!(!allowlist.contains("192.168.0.3"));
+        allowlist.add("192.168.0.3").unwrap();
+
+        let reloaded = Allowlist::load(Some(path.clone())).unwrap();
+        assert!(reloaded.contains("192.168.0.3"));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,133 @@
+use anyhow::{anyhow, Result};
+use enigo::{Enigo, KeyboardControllable, MouseControllable};
+
+use crate::protocol::{Key, MouseButton, Vec2};
+
+use super::{display_rect, InputBackend};
+
+/// An input backend that uses enigo.
+pub struct EnigoBackend {
+    enigo: Enigo,
+}
+
+impl EnigoBackend {
+    pub fn new() -> Self {
+        Self { enigo: Enigo::new() }
+    }
+}
+
+fn to_enigo_button(mouse_button: MouseButton) -> Result<enigo::MouseButton> {
+    match mouse_button {
+        MouseButton::Left => Ok(enigo::MouseButton::Left),
+        MouseButton::Middle => Ok(enigo::MouseButton::Middle),
+        MouseButton::Right => Ok(enigo::MouseButton::Right),
+        MouseButton::ScrollUp => Ok(enigo::MouseButton::ScrollUp),
+        MouseButton::ScrollDown => Ok(enigo::MouseButton::ScrollDown),
+        MouseButton::ScrollLeft => Ok(enigo::MouseButton::ScrollLeft),
+        MouseButton::ScrollRight => Ok(enigo::MouseButton::ScrollRight),
+        MouseButton::Back | MouseButton::Forward => Err(anyhow!("Mouse button {:?} is not supported by enigo", mouse_button)),
+    }
+}
+
+fn to_enigo_key(key: Key) -> enigo::Key {
+    match key {
+        Key::Alt => enigo::Key::Alt,
+        Key::Control => enigo::Key::Control,
+        Key::Shift => enigo::Key::Shift,
+        Key::Meta => enigo::Key::Meta,
+        Key::CapsLock => enigo::Key::CapsLock,
+        Key::Backspace => enigo::Key::Backspace,
+        Key::Delete => enigo::Key::Delete,
+        Key::Return => enigo::Key::Return,
+        Key::Space => enigo::Key::Space,
+        Key::Tab => enigo::Key::Tab,
+        Key::Escape => enigo::Key::Escape,
+        Key::UpArrow => enigo::Key::UpArrow,
+        Key::DownArrow => enigo::Key::DownArrow,
+        Key::LeftArrow => enigo::Key::LeftArrow,
+        Key::RightArrow => enigo::Key::RightArrow,
+        Key::Home => enigo::Key::Home,
+        Key::End => enigo::Key::End,
+        Key::PageUp => enigo::Key::PageUp,
+        Key::PageDown => enigo::Key::PageDown,
+        Key::F1 => enigo::Key::F1,
+        Key::F2 => enigo::Key::F2,
+        Key::F3 => enigo::Key::F3,
+        Key::F4 => enigo::Key::F4,
+        Key::F5 => enigo::Key::F5,
+        Key::F6 => enigo::Key::F6,
+        Key::F7 => enigo::Key::F7,
+        Key::F8 => enigo::Key::F8,
+        Key::F9 => enigo::Key::F9,
+        Key::F10 => enigo::Key::F10,
+        Key::F11 => enigo::Key::F11,
+        Key::F12 => enigo::Key::F12,
+        Key::Unicode(c) => enigo::Key::Layout(c),
+        Key::Raw(code) => enigo::Key::Raw(code),
+    }
+}
+
+impl InputBackend for EnigoBackend {
+    fn key_sequence(&mut self, text: &str) -> Result<()> {
+        self.enigo.key_sequence(text);
+        Ok(())
+    }
+
+    fn key_down(&mut self, key: Key) -> Result<()> {
+        self.enigo.key_down(to_enigo_key(key));
+        Ok(())
+    }
+
+    fn key_up(&mut self, key: Key) -> Result<()> {
+        self.enigo.key_up(to_enigo_key(key));
+        Ok(())
+    }
+
+    fn key_click(&mut self, key: Key) -> Result<()> {
+        self.enigo.key_click(to_enigo_key(key));
+        Ok(())
+    }
+
+    fn mouse_move_to(&mut self, point: Vec2<i32>) -> Result<()> {
+        self.enigo.mouse_move_to(point.x, point.y);
+        Ok(())
+    }
+
+    fn mouse_move_by(&mut self, delta: Vec2<i32>) -> Result<()> {
+        self.enigo.mouse_move_relative(delta.x, delta.y);
+        Ok(())
+    }
+
+    fn mouse_move_to_normalized(&mut self, point: Vec2<f64>, display: Option<usize>) -> Result<()> {
+        let rect = display_rect(display).ok_or_else(|| anyhow!("Could not determine bounds of display {:?}", display))?;
+        let x = rect.x0 + point.x.clamp(0.0, 1.0) * rect.width();
+        let y = rect.y0 + point.y.clamp(0.0, 1.0) * rect.height();
+        self.enigo.mouse_move_to(x.round() as i32, y.round() as i32);
+        Ok(())
+    }
+
+    fn mouse_down(&mut self, button: MouseButton) -> Result<()> {
+        self.enigo.mouse_down(to_enigo_button(button)?);
+        Ok(())
+    }
+
+    fn mouse_up(&mut self, button: MouseButton) -> Result<()> {
+        self.enigo.mouse_up(to_enigo_button(button)?);
+        Ok(())
+    }
+
+    fn mouse_click(&mut self, button: MouseButton) -> Result<()> {
+        self.enigo.mouse_click(to_enigo_button(button)?);
+        Ok(())
+    }
+
+    fn scroll(&mut self, delta: Vec2<i32>) -> Result<()> {
+        if delta.x != 0 {
+            self.enigo.mouse_scroll_x(delta.x);
+        }
+        if delta.y != 0 {
+            self.enigo.mouse_scroll_y(delta.y);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,104 @@
+mod enigo;
+mod recording;
+#[cfg(all(target_os = "linux", feature = "uinput"))]
+mod uinput;
+
+pub use self::enigo::*;
+pub use recording::*;
+#[cfg(all(target_os = "linux", feature = "uinput"))]
+pub use self::uinput::*;
+
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::ValueEnum;
+use druid::{Rect, Screen};
+
+use crate::protocol::{Key, MouseButton, Vec2};
+
+/// A facility for injecting keyboard and mouse input.
+pub trait InputBackend {
+    /// Types the given text.
+    fn key_sequence(&mut self, text: &str) -> Result<()>;
+
+    /// Presses the given key.
+    fn key_down(&mut self, key: Key) -> Result<()>;
+
+    /// Releases the given key.
+    fn key_up(&mut self, key: Key) -> Result<()>;
+
+    /// Presses and releases the given key.
+    fn key_click(&mut self, key: Key) -> Result<()> {
+        self.key_down(key.clone())?;
+        self.key_up(key)
+    }
+
+    /// Moves the mouse to the given point in screen coordinates.
+    fn mouse_move_to(&mut self, point: Vec2<i32>) -> Result<()>;
+
+    /// Moves the mouse relative to its current position.
+    fn mouse_move_by(&mut self, delta: Vec2<i32>) -> Result<()>;
+
+    /// Moves the mouse to a point with coordinates from 0.0 to 1.0, relative
+    /// to the given display (or the entire virtual screen if absent).
+    fn mouse_move_to_normalized(&mut self, point: Vec2<f64>, display: Option<usize>) -> Result<()>;
+
+    /// Presses the given mouse button.
+    fn mouse_down(&mut self, button: MouseButton) -> Result<()>;
+
+    /// Releases the given mouse button.
+    fn mouse_up(&mut self, button: MouseButton) -> Result<()>;
+
+    /// Presses and releases the given mouse button.
+    fn mouse_click(&mut self, button: MouseButton) -> Result<()> {
+        self.mouse_down(button.clone())?;
+        self.mouse_up(button)
+    }
+
+    /// Scrolls by whole lines/columns, positive values scrolling down/right.
+    fn scroll(&mut self, delta: Vec2<i32>) -> Result<()>;
+}
+
+/// Fetches the bounds of the given display or, if none is given,
+/// the bounds of the virtual screen spanning all displays.
+fn display_rect(display: Option<usize>) -> Option<Rect> {
+    match display {
+        Some(i) => Screen::get_monitors().get(i).map(|m| m.virtual_rect()),
+        None => Some(Screen::get_display_rect()).filter(|r| r.area() > 0.0),
+    }
+}
+
+/// The available input backends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum BackendKind {
+    /// Injects input through enigo (X11 on Linux).
+    Enigo,
+    /// Injects input through virtual devices created via /dev/uinput.
+    #[cfg(all(target_os = "linux", feature = "uinput"))]
+    Uinput,
+    /// Only records the input (see `--dry-run`).
+    DryRun,
+}
+
+/// The configuration of the input backend.
+#[derive(Debug, Clone)]
+pub struct BackendConfig {
+    pub kind: BackendKind,
+    /// A JSON-lines file for the dry-run backend to record to.
+    pub record_path: Option<PathBuf>,
+}
+
+impl BackendConfig {
+    /// Creates the configured backend.
+    pub fn create(&self) -> Result<Box<dyn InputBackend>> {
+        match self.kind {
+            BackendKind::Enigo => Ok(Box::new(EnigoBackend::new())),
+            #[cfg(all(target_os = "linux", feature = "uinput"))]
+            BackendKind::Uinput => Ok(Box::new(UinputBackend::new()?)),
+            BackendKind::DryRun => match &self.record_path {
+                Some(path) => Ok(Box::new(RecordingBackend::with_file(path)?)),
+                None => Ok(Box::new(RecordingBackend::new())),
+            },
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use std::{fs::File, io::{BufWriter, Write}, path::Path};
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+
+use crate::protocol::{Action, Key, MouseButton, Vec2};
+
+use super::InputBackend;
+
+/// An input backend that does not touch the OS, but records the actions
+/// it would have performed (after the controller decomposed them, e.g.
+/// chords into key presses). Useful for testing and debugging.
+///
+/// Outside of tests, the actions are only written to the file (if any),
+/// so a long-running dry-run server does not accumulate them in memory.
+pub struct RecordingBackend {
+    #[cfg(test)]
+    log: Arc<Mutex<Vec<Action>>>,
+    file: Option<BufWriter<File>>,
+}
+
+impl RecordingBackend {
+    pub fn new() -> Self {
+        Self {
+            #[cfg(test)]
+            log: Arc::new(Mutex::new(Vec::new())),
+            file: None,
+        }
+    }
+
+    /// Creates a backend that additionally appends the actions to the
+    /// given file as JSON lines.
+    pub fn with_file(path: impl AsRef<Path>) -> Result<Self> {
+        let file = File::options().create(true).append(true).open(path)?;
+        let mut backend = Self::new();
+        backend.file = Some(BufWriter::new(file));
+        Ok(backend)
+    }
+
+    /// A handle to the in-memory log of recorded actions.
+    #[cfg(test)]
+    pub fn log(&self) -> Arc<Mutex<Vec<Action>>> {
+        self.log.clone()
+    }
+
+    fn record(&mut self, action: Action) -> Result<()> {
+        if let Some(file) = &mut self.file {
+            serde_json::to_writer(&mut *file, &action)?;
+            writeln!(file)?;
+            file.flush()?;
+        }
+        #[cfg(test)]
+        self.log.lock().unwrap().push(action);
+        Ok(())
+    }
+}
+
+impl InputBackend for RecordingBackend {
+    fn key_sequence(&mut self, text: &str) -> Result<()> {
+        self.record(Action::KeySequence { text: text.to_owned() })
+    }
+
+    fn key_down(&mut self, key: Key) -> Result<()> {
+        self.record(Action::KeyDown { key })
+    }
+
+    fn key_up(&mut self, key: Key) -> Result<()> {
+        self.record(Action::KeyUp { key })
+    }
+
+    fn key_click(&mut self, key: Key) -> Result<()> {
+        self.record(Action::KeyClick { key })
+    }
+
+    fn mouse_move_to(&mut self, point: Vec2<i32>) -> Result<()> {
+        self.record(Action::MouseMoveTo { point })
+    }
+
+    fn mouse_move_by(&mut self, delta: Vec2<i32>) -> Result<()> {
+        self.record(Action::MouseMoveBy { delta })
+    }
+
+    fn mouse_move_to_normalized(&mut self, point: Vec2<f64>, display: Option<usize>) -> Result<()> {
+        self.record(Action::MouseMoveToNormalized { point, display })
+    }
+
+    fn mouse_down(&mut self, button: MouseButton) -> Result<()> {
+        self.record(Action::MouseDown { button })
+    }
+
+    fn mouse_up(&mut self, button: MouseButton) -> Result<()> {
+        self.record(Action::MouseUp { button })
+    }
+
+    fn mouse_click(&mut self, button: MouseButton) -> Result<()> {
+        self.record(Action::MouseClick { button, count: 1 })
+    }
+
+    fn scroll(&mut self, delta: Vec2<i32>) -> Result<()> {
+        self.record(Action::Scroll { delta })
+    }
+}
@@ -0,0 +1,103 @@
+use evdev::Key as EvKey;
+
+use crate::protocol::Key;
+
+/// Maps a key to its evdev keycode, along with whether shift needs to be held.
+pub fn to_evdev_key(key: Key) -> Option<(EvKey, bool)> {
+    let ev_key = match key {
+        Key::Alt => EvKey::KEY_LEFTALT,
+        Key::Control => EvKey::KEY_LEFTCTRL,
+        Key::Shift => EvKey::KEY_LEFTSHIFT,
+        Key::Meta => EvKey::KEY_LEFTMETA,
+        Key::CapsLock => EvKey::KEY_CAPSLOCK,
+        Key::Backspace => EvKey::KEY_BACKSPACE,
+        Key::Delete => EvKey::KEY_DELETE,
+        Key::Return => EvKey::KEY_ENTER,
+        Key::Space => EvKey::KEY_SPACE,
+        Key::Tab => EvKey::KEY_TAB,
+        Key::Escape => EvKey::KEY_ESC,
+        Key::UpArrow => EvKey::KEY_UP,
+        Key::DownArrow => EvKey::KEY_DOWN,
+        Key::LeftArrow => EvKey::KEY_LEFT,
+        Key::RightArrow => EvKey::KEY_RIGHT,
+        Key::Home => EvKey::KEY_HOME,
+        Key::End => EvKey::KEY_END,
+        Key::PageUp => EvKey::KEY_PAGEUP,
+        Key::PageDown => EvKey::KEY_PAGEDOWN,
+        Key::F1 => EvKey::KEY_F1,
+        Key::F2 => EvKey::KEY_F2,
+        Key::F3 => EvKey::KEY_F3,
+        Key::F4 => EvKey::KEY_F4,
+        Key::F5 => EvKey::KEY_F5,
+        Key::F6 => EvKey::KEY_F6,
+        Key::F7 => EvKey::KEY_F7,
+        Key::F8 => EvKey::KEY_F8,
+        Key::F9 => EvKey::KEY_F9,
+        Key::F10 => EvKey::KEY_F10,
+        Key::F11 => EvKey::KEY_F11,
+        Key::F12 => EvKey::KEY_F12,
+        Key::Unicode(c) => return char_to_evdev_key(c),
+        Key::Raw(code) => EvKey::new(code),
+    };
+    Some((ev_key, false))
+}
+
+/// Maps a character to the evdev keycode producing it on a US layout,
+/// along with whether shift needs to be held.
+pub fn char_to_evdev_key(c: char) -> Option<(EvKey, bool)> {
+    let shifted = c.is_ascii_uppercase() || "~!@#$%^&*()_+{}|:\"<>?".contains(c);
+    let ev_key = match c.to_ascii_lowercase() {
+        'a' => EvKey::KEY_A,
+        'b' => EvKey::KEY_B,
+        'c' => EvKey::KEY_C,
+        'd' => EvKey::KEY_D,
+        'e' => EvKey::KEY_E,
+        'f' => EvKey::KEY_F,
+        'g' => EvKey::KEY_G,
+        'h' => EvKey::KEY_H,
+        'i' => EvKey::KEY_I,
+        'j' => EvKey::KEY_J,
+        'k' => EvKey::KEY_K,
+        'l' => EvKey::KEY_L,
+        'm' => EvKey::KEY_M,
+        'n' => EvKey::KEY_N,
+        'o' => EvKey::KEY_O,
+        'p' => EvKey::KEY_P,
+        'q' => EvKey::KEY_Q,
+        'r' => EvKey::KEY_R,
+        's' => EvKey::KEY_S,
+        't' => EvKey::KEY_T,
+        'u' => EvKey::KEY_U,
+        'v' => EvKey::KEY_V,
+        'w' => EvKey::KEY_W,
+        'x' => EvKey::KEY_X,
+        'y' => EvKey::KEY_Y,
+        'z' => EvKey::KEY_Z,
+        '1' | '!' => EvKey::KEY_1,
+        '2' | '@' => EvKey::KEY_2,
+        '3' | '#' => EvKey::KEY_3,
+        '4' | '$' => EvKey::KEY_4,
+        '5' | '%' => EvKey::KEY_5,
+        '6' | '^' => EvKey::KEY_6,
+        '7' | '&' => EvKey::KEY_7,
+        '8' | '*' => EvKey::KEY_8,
+        '9' | '(' => EvKey::KEY_9,
+        '0' | ')' => EvKey::KEY_0,
+        '-' | '_' => EvKey::KEY_MINUS,
+        '=' | '+' => EvKey::KEY_EQUAL,
+        '[' | '{' => EvKey::KEY_LEFTBRACE,
+        ']' | '}' => EvKey::KEY_RIGHTBRACE,
+        '\\' | '|' => EvKey::KEY_BACKSLASH,
+        ';' | ':' => EvKey::KEY_SEMICOLON,
+        '\'' | '"' => EvKey::KEY_APOSTROPHE,
+        '`' | '~' => EvKey::KEY_GRAVE,
+        ',' | '<' => EvKey::KEY_COMMA,
+        '.' | '>' => EvKey::KEY_DOT,
+        '/' | '?' => EvKey::KEY_SLASH,
+        ' ' => EvKey::KEY_SPACE,
+        '\t' => EvKey::KEY_TAB,
+        '\n' => EvKey::KEY_ENTER,
+        _ => return None,
+    };
+    Some((ev_key, shifted))
+}
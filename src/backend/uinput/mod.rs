@@ -0,0 +1,206 @@
+mod keymap;
+
+use std::{thread, time::Duration};
+
+use anyhow::{anyhow, Result};
+use evdev::{uinput::{VirtualDevice, VirtualDeviceBuilder}, AttributeSet, AbsInfo, AbsoluteAxisType, EventType, InputEvent, Key as EvKey, RelativeAxisType, UinputAbsSetup};
+
+use crate::protocol::{Key, MouseButton, Vec2};
+
+use self::keymap::{to_evdev_key, char_to_evdev_key};
+use super::{display_rect, InputBackend};
+
+/// The maximum value of the absolute pointer's axes.
+const ABS_MAX: i32 = 65535;
+
+/// An input backend that creates virtual devices through `/dev/uinput`.
+/// Unlike enigo, this works independently of the display server (e.g. on Wayland),
+/// but requires write access to `/dev/uinput`.
+pub struct UinputBackend {
+    /// A keyboard and relative mouse.
+    device: VirtualDevice,
+    /// An absolute pointer for moving to points.
+    pointer: VirtualDevice,
+}
+
+fn to_evdev_button(mouse_button: MouseButton) -> Option<EvKey> {
+    match mouse_button {
+        MouseButton::Left => Some(EvKey::BTN_LEFT),
+        MouseButton::Middle => Some(EvKey::BTN_MIDDLE),
+        MouseButton::Right => Some(EvKey::BTN_RIGHT),
+        MouseButton::Back => Some(EvKey::BTN_SIDE),
+        MouseButton::Forward => Some(EvKey::BTN_EXTRA),
+        MouseButton::ScrollUp | MouseButton::ScrollDown | MouseButton::ScrollLeft | MouseButton::ScrollRight => None,
+    }
+}
+
+/// The scroll delta equivalent to pressing one of the scroll buttons.
+fn scroll_delta(mouse_button: MouseButton) -> Vec2<i32> {
+    match mouse_button {
+        MouseButton::ScrollUp => Vec2 { x: 0, y: -1 },
+        MouseButton::ScrollDown => Vec2 { x: 0, y: 1 },
+        MouseButton::ScrollLeft => Vec2 { x: -1, y: 0 },
+        MouseButton::ScrollRight => Vec2 { x: 1, y: 0 },
+        _ => Vec2::default(),
+    }
+}
+
+fn key_event(key: EvKey, pressed: bool) -> InputEvent {
+    InputEvent::new(EventType::KEY, key.code(), pressed as i32)
+}
+
+fn rel_event(axis: RelativeAxisType, value: i32) -> InputEvent {
+    InputEvent::new(EventType::RELATIVE, axis.0, value)
+}
+
+fn abs_event(axis: AbsoluteAxisType, value: i32) -> InputEvent {
+    InputEvent::new(EventType::ABSOLUTE, axis.0, value)
+}
+
+impl UinputBackend {
+    pub fn new() -> Result<Self> {
+        // Regular keys occupy the codes up to KEY_MICMUTE, above are buttons
+        let mut keys: AttributeSet<EvKey> = (1..=EvKey::KEY_MICMUTE.code()).map(EvKey::new).collect();
+        for button in [EvKey::BTN_LEFT, EvKey::BTN_RIGHT, EvKey::BTN_MIDDLE, EvKey::BTN_SIDE, EvKey::BTN_EXTRA] {
+            keys.insert(button);
+        }
+        let rel_axes: AttributeSet<RelativeAxisType> = [
+            RelativeAxisType::REL_X,
+            RelativeAxisType::REL_Y,
+            RelativeAxisType::REL_WHEEL,
+            RelativeAxisType::REL_HWHEEL,
+        ].into_iter().collect();
+
+        let device = VirtualDeviceBuilder::new()?
+            .name("Robo Virtual Input")
+            .with_keys(&keys)?
+            .with_relative_axes(&rel_axes)?
+            .build()?;
+
+        // An absolute pointer needs a button to be recognized as such, we only use it for motion though
+        let pointer_keys: AttributeSet<EvKey> = [EvKey::BTN_LEFT].into_iter().collect();
+        let abs_info = AbsInfo::new(0, 0, ABS_MAX, 0, 0, 0);
+
+        let pointer = VirtualDeviceBuilder::new()?
+            .name("Robo Virtual Pointer")
+            .with_keys(&pointer_keys)?
+            .with_absolute_axis(&UinputAbsSetup::new(AbsoluteAxisType::ABS_X, abs_info))?
+            .with_absolute_axis(&UinputAbsSetup::new(AbsoluteAxisType::ABS_Y, abs_info))?
+            .build()?;
+
+        // Give udev and the compositor some time to pick up the new devices,
+        // otherwise the first events may get lost
+        thread::sleep(Duration::from_millis(200));
+
+        Ok(Self { device, pointer })
+    }
+
+    fn key(&mut self, key: Key, pressed: bool) -> Result<()> {
+        let (ev_key, shifted) = to_evdev_key(key.clone()).ok_or_else(|| anyhow!("Key {:?} is not supported by uinput", key))?;
+        self.ev_key(ev_key, shifted, pressed)
+    }
+
+    fn ev_key(&mut self, ev_key: EvKey, shifted: bool, pressed: bool) -> Result<()> {
+        let mut events = vec![key_event(ev_key, pressed)];
+        if shifted {
+            let shift = key_event(EvKey::KEY_LEFTSHIFT, pressed);
+            if pressed {
+                events.insert(0, shift);
+            } else {
+                events.push(shift);
+            }
+        }
+        self.device.emit(&events)?;
+        Ok(())
+    }
+
+    /// Moves the absolute pointer to a point with coordinates from 0.0 to 1.0,
+    /// relative to the entire virtual screen.
+    fn move_pointer(&mut self, point: Vec2<f64>) -> Result<()> {
+        let x = (point.x.clamp(0.0, 1.0) * ABS_MAX as f64).round() as i32;
+        let y = (point.y.clamp(0.0, 1.0) * ABS_MAX as f64).round() as i32;
+        self.pointer.emit(&[abs_event(AbsoluteAxisType::ABS_X, x), abs_event(AbsoluteAxisType::ABS_Y, y)])?;
+        Ok(())
+    }
+}
+
+impl InputBackend for UinputBackend {
+    fn key_sequence(&mut self, text: &str) -> Result<()> {
+        // Map the entire text first, so we don't type only part of it
+        let keys = text.chars()
+            .map(|c| char_to_evdev_key(c).ok_or_else(|| anyhow!("Character {:?} cannot be typed with uinput", c)))
+            .collect::<Result<Vec<_>>>()?;
+        for (ev_key, shifted) in keys {
+            self.ev_key(ev_key, shifted, true)?;
+            self.ev_key(ev_key, shifted, false)?;
+        }
+        Ok(())
+    }
+
+    fn key_down(&mut self, key: Key) -> Result<()> {
+        self.key(key, true)
+    }
+
+    fn key_up(&mut self, key: Key) -> Result<()> {
+        self.key(key, false)
+    }
+
+    fn mouse_move_to(&mut self, point: Vec2<i32>) -> Result<()> {
+        let screen = display_rect(None).ok_or_else(|| anyhow!("Could not determine screen bounds"))?;
+        self.move_pointer(Vec2 {
+            x: (point.x as f64 - screen.x0) / screen.width(),
+            y: (point.y as f64 - screen.y0) / screen.height(),
+        })
+    }
+
+    fn mouse_move_by(&mut self, delta: Vec2<i32>) -> Result<()> {
+        self.device.emit(&[rel_event(RelativeAxisType::REL_X, delta.x), rel_event(RelativeAxisType::REL_Y, delta.y)])?;
+        Ok(())
+    }
+
+    fn mouse_move_to_normalized(&mut self, point: Vec2<f64>, display: Option<usize>) -> Result<()> {
+        match display {
+            // The absolute pointer spans the virtual screen, so no conversion is needed
+            None => self.move_pointer(point),
+            Some(_) => {
+                let screen = display_rect(None).ok_or_else(|| anyhow!("Could not determine screen bounds"))?;
+                let rect = display_rect(display).ok_or_else(|| anyhow!("Could not determine bounds of display {:?}", display))?;
+                self.move_pointer(Vec2 {
+                    x: (rect.x0 + point.x.clamp(0.0, 1.0) * rect.width() - screen.x0) / screen.width(),
+                    y: (rect.y0 + point.y.clamp(0.0, 1.0) * rect.height() - screen.y0) / screen.height(),
+                })
+            },
+        }
+    }
+
+    fn mouse_down(&mut self, button: MouseButton) -> Result<()> {
+        match to_evdev_button(button.clone()) {
+            Some(ev_button) => self.device.emit(&[key_event(ev_button, true)])?,
+            // Like on X11, pressing a scroll button scrolls by one step
+            None => self.scroll(scroll_delta(button))?,
+        }
+        Ok(())
+    }
+
+    fn mouse_up(&mut self, button: MouseButton) -> Result<()> {
+        if let Some(ev_button) = to_evdev_button(button) {
+            self.device.emit(&[key_event(ev_button, false)])?;
+        }
+        Ok(())
+    }
+
+    fn scroll(&mut self, delta: Vec2<i32>) -> Result<()> {
+        let mut events = Vec::new();
+        if delta.x != 0 {
+            events.push(rel_event(RelativeAxisType::REL_HWHEEL, delta.x));
+        }
+        if delta.y != 0 {
+            // Positive wheel values scroll up in evdev
+            events.push(rel_event(RelativeAxisType::REL_WHEEL, -delta.y));
+        }
+        if !events.is_empty() {
+            self.device.emit(&events)?;
+        }
+        Ok(())
+    }
+}
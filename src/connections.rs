@@ -0,0 +1,73 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}};
+
+use tokio::sync::mpsc;
+
+use crate::{security::DeviceInfo, server::ClientId};
+
+/// A command sent to the task handling a connection.
+#[derive(Debug)]
+pub enum ConnectionCommand {
+    /// Closes the connection.
+    Disconnect,
+}
+
+struct Connection {
+    commands: mpsc::UnboundedSender<ConnectionCommand>,
+    /// The id of the paired device the client identified as, if any.
+    device: Option<String>,
+}
+
+/// The command channels of the connected clients, which let other
+/// parts of the app (e.g. the GUI) control the per-client tasks.
+#[derive(Clone, Default)]
+pub struct Connections {
+    connections: Arc<Mutex<HashMap<ClientId, Connection>>>,
+}
+
+impl Connections {
+    /// Registers a client, returning the receiver for its commands.
+    pub fn register(&self, client: ClientId) -> mpsc::UnboundedReceiver<ConnectionCommand> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.connections.lock().unwrap().insert(client, Connection { commands: tx, device: None });
+        rx
+    }
+
+    pub fn unregister(&self, client: ClientId) {
+        self.connections.lock().unwrap().remove(&client);
+    }
+
+    /// Associates a client with the paired device it identified as.
+    pub fn set_device(&self, client: ClientId, device: String) {
+        if let Some(connection) = self.connections.lock().unwrap().get_mut(&client) {
+            connection.device = Some(device);
+        }
+    }
+
+    fn send(&self, client: ClientId, command: ConnectionCommand) {
+        if let Some(connection) = self.connections.lock().unwrap().get(&client) {
+            // The client may be disconnecting already
+            let _ = connection.commands.send(command);
+        }
+    }
+
+    /// Disconnects the given client.
+    pub fn disconnect(&self, client: ClientId) {
+        self.send(client, ConnectionCommand::Disconnect);
+    }
+
+    /// Disconnects all clients.
+    pub fn disconnect_all(&self) {
+        for connection in self.connections.lock().unwrap().values() {
+            let _ = connection.commands.send(ConnectionCommand::Disconnect);
+        }
+    }
+
+    /// Disconnects the clients whose device is not among the given paired ones (anymore).
+    pub fn disconnect_revoked(&self, paired: &[DeviceInfo]) {
+        for connection in self.connections.lock().unwrap().values() {
+            if matches!(&connection.device, Some(device) if !paired.iter().any(|d| &d.id == device)) {
+                let _ = connection.commands.send(ConnectionCommand::Disconnect);
+            }
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use std::{sync::Mutex, time::{Duration, Instant}};
+
+use clap::ValueEnum;
+use tokio::sync::watch;
+
+use crate::{protocol::ControlStatus, server::ClientId};
+
+/// The policies for sharing control among multiple clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ControlPolicy {
+    /// All clients may act at any time.
+    Shared,
+    /// The first client to act holds control until it releases it or disconnects.
+    FirstCome,
+    /// Clients have to request control before acting, holding it until they release it or disconnect.
+    Explicit,
+    /// The most recent client to act takes over control once the holder has been idle for the cooldown.
+    MostRecent,
+}
+
+/// The outcome of trying to acquire control.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Acquire {
+    /// The client holds control (now).
+    Granted,
+    /// The client took over control from the given client.
+    TakenFrom(ClientId),
+    /// Another client holds control.
+    Denied,
+}
+
+struct Holder {
+    client: ClientId,
+    last_active: Instant,
+}
+
+/// Ensures that at most one client at a time controls the input,
+/// depending on the policy.
+pub struct ControlLock {
+    policy: ControlPolicy,
+    /// How long the holder has to be idle before another client may take over.
+    cooldown: Duration,
+    holder: Mutex<Option<Holder>>,
+    updates: watch::Sender<Option<ClientId>>,
+}
+
+impl ControlLock {
+    pub fn new(policy: ControlPolicy, cooldown: Duration) -> Self {
+        let (updates, _) = watch::channel(None);
+        Self { policy, cooldown, holder: Mutex::new(None), updates }
+    }
+
+    /// Tries to acquire control for the given client, either because it
+    /// wants to act or because it explicitly requested control.
+    pub fn acquire(&self, client: ClientId, explicit: bool) -> Acquire {
+        if self.policy == ControlPolicy::Shared {
+            return Acquire::Granted;
+        }
+
+        let mut holder = self.holder.lock().unwrap();
+        let now = Instant::now();
+        let outcome = match &mut *holder {
+            Some(h) if h.client == client => {
+                h.last_active = now;
+                return Acquire::Granted;
+            },
+            Some(h) if self.policy == ControlPolicy::MostRecent && now.duration_since(h.last_active) >= self.cooldown => Acquire::TakenFrom(h.client),
+            Some(_) => return Acquire::Denied,
+            None if self.policy == ControlPolicy::Explicit && !explicit => return Acquire::Denied,
+            None => Acquire::Granted,
+        };
+        *holder = Some(Holder { client, last_active: now });
+        self.updates.send_replace(Some(client));
+        outcome
+    }
+
+    /// Releases control if the given client holds it.
+    pub fn release(&self, client: ClientId) {
+        let mut holder = self.holder.lock().unwrap();
+        if matches!(&*holder, Some(h) if h.client == client) {
+            *holder = None;
+            self.updates.send_replace(None);
+        }
+    }
+
+    /// The control status from the given client's perspective, if control is exclusive.
+    pub fn status(&self, client: ClientId) -> Option<ControlStatus> {
+        if self.policy == ControlPolicy::Shared {
+            return None;
+        }
+        Some(match &*self.holder.lock().unwrap() {
+            None => ControlStatus::Free,
+            Some(h) if h.client == client => ControlStatus::Yours,
+            Some(_) => ControlStatus::Taken,
+        })
+    }
+
+    /// Subscribes to changes of the client holding control.
+    pub fn subscribe(&self) -> watch::Receiver<Option<ClientId>> {
+        self.updates.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use crate::{protocol::ControlStatus, server::ClientId};
+
+    use super::{Acquire, ControlLock, ControlPolicy};
+
+    #[test]
+    fn first_come_holds_until_released() {
+        let (a, b) = (ClientId::next(), ClientId::next());
+        let lock = ControlLock::new(ControlPolicy::FirstCome, Duration::ZERO);
+
+        assert_eq!(lock.acquire(a, false), Acquire::Granted);
+        assert_eq!(lock.acquire(b, false), Acquire::Denied);
+        assert_eq!(lock.status(b), Some(ControlStatus::Taken));
+
+        lock.release(a);
+        assert_eq!(lock.acquire(b, false), Acquire::Granted);
+        assert_eq!(lock.status(b), Some(ControlStatus::Yours));
+    }
+
+    #[test]
+    fn explicit_requires_request() {
+        let a = ClientId::next();
+        let lock = ControlLock::new(ControlPolicy::Explicit, Duration::ZERO);
+
+        assert_eq!(lock.acquire(a, false), Acquire::Denied);
+        assert_eq!(lock.status(a), Some(ControlStatus::Free));
+        assert_eq!(lock.acquire(a, true), Acquire::Granted);
+        assert_eq!(lock.acquire(a, false), Acquire::Granted);
+    }
+
+    #[test]
+    fn most_recent_takes_over_after_cooldown() {
+        let (a, b) = (ClientId::next(), ClientId::next());
+        let lock = ControlLock::new(ControlPolicy::MostRecent, Duration::from_millis(50));
+
+        assert_eq!(lock.acquire(a, false), Acquire::Granted);
+        assert_eq!(lock.acquire(b, false), Acquire::Denied);
+
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(lock.acquire(b, false), Acquire::TakenFrom(a));
+        assert_eq!(lock.acquire(a, false), Acquire::Denied);
+    }
+}
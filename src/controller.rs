@@ -1,32 +1,239 @@
-use enigo::{Enigo, KeyboardControllable, MouseControllable};
+use std::collections::HashMap;
 
-use crate::protocol::{Action, MouseButton};
+use anyhow::Result;
+
+use crate::{backend::InputBackend, pause::Pause, protocol::{Action, Key, Modifier, MouseButton, Vec2}, server::ClientId};
 
 pub struct Controller {
-    enigo: Enigo,
+    backend: Box<dyn InputBackend>,
+    /// Each client's sub-line scroll motion that has not been performed yet.
+    scroll_remainders: HashMap<ClientId, Vec2<f64>>,
+    /// The keys and buttons each client is currently holding down.
+    held: HashMap<ClientId, HeldInput>,
+    /// Actions are ignored while paused.
+    pause: Pause,
+}
+
+/// Keys and mouse buttons that are pressed, in the order they were pressed in.
+#[derive(Default)]
+struct HeldInput {
+    keys: Vec<Key>,
+    buttons: Vec<MouseButton>,
 }
 
-fn to_enigo_button(mouse_button: MouseButton) -> enigo::MouseButton {
-    match mouse_button {
-        MouseButton::Left => enigo::MouseButton::Left,
-        MouseButton::Middle => enigo::MouseButton::Middle,
-        MouseButton::Right => enigo::MouseButton::Right,
+/// Holds modifiers down for as long as it lives, releasing them
+/// in reverse order when dropped (even if the chord's key step fails).
+struct HeldModifiers<'a> {
+    backend: &'a mut dyn InputBackend,
+    modifiers: Vec<Modifier>,
+}
+
+impl<'a> HeldModifiers<'a> {
+    fn press(backend: &'a mut dyn InputBackend, modifiers: Vec<Modifier>) -> Result<Self> {
+        let mut held = Self { backend, modifiers: Vec::with_capacity(modifiers.len()) };
+        for modifier in modifiers {
+            held.backend.key_down(modifier.into())?;
+            held.modifiers.push(modifier);
+        }
+        Ok(held)
+    }
+}
+
+impl<'a> Drop for HeldModifiers<'a> {
+    fn drop(&mut self) {
+        for modifier in self.modifiers.drain(..).rev() {
+            let _ = self.backend.key_up(modifier.into());
+        }
     }
 }
 
 impl Controller {
-    pub fn new() -> Self {
-        Self { enigo: Enigo::new() }
+    pub fn new(backend: Box<dyn InputBackend>, pause: Pause) -> Self {
+        Self { backend, scroll_remainders: HashMap::new(), held: HashMap::new(), pause }
+    }
+
+    fn held_by(&mut self, client: ClientId) -> &mut HeldInput {
+        self.held.entry(client).or_default()
+    }
+
+    /// Releases all keys and buttons held down by the given client, e.g.
+    /// because it disconnected before it could release them itself.
+    pub fn release(&mut self, client: ClientId) -> Result<()> {
+        self.scroll_remainders.remove(&client);
+        let held = match self.held.remove(&client) {
+            Some(held) => held,
+            None => return Ok(()),
+        };
+        // We try to release everything, even if some of it fails
+        let mut result = Ok(());
+        for key in held.keys.into_iter().rev() {
+            result = result.and(self.backend.key_up(key));
+        }
+        for button in held.buttons.into_iter().rev() {
+            result = result.and(self.backend.mouse_up(button));
+        }
+        result
+    }
+
+    /// Releases all keys and buttons held down by any client.
+    pub fn release_all(&mut self) -> Result<()> {
+        self.scroll_remainders.clear();
+        let clients: Vec<_> = self.held.keys().copied().collect();
+        let mut result = Ok(());
+        for client in clients {
+            result = result.and(self.release(client));
+        }
+        result
+    }
+
+    fn smooth_scroll(&mut self, client: ClientId, delta: Vec2<f64>) -> Result<()> {
+        let remainder = self.scroll_remainders.entry(client).or_default();
+        let x = remainder.x + delta.x;
+        let y = remainder.y + delta.y;
+        *remainder = Vec2 { x: x.fract(), y: y.fract() };
+        let lines = Vec2 { x: x.trunc() as i32, y: y.trunc() as i32 };
+        if lines.x == 0 && lines.y == 0 {
+            return Ok(());
+        }
+        self.backend.scroll(lines)
     }
 
-    pub fn perform(&mut self, action: Action) {
+    pub fn perform(&mut self, client: ClientId, action: Action) -> Result<()> {
+        // Actions may have been forwarded just before pausing
+        if self.pause.is_paused() {
+            return Ok(());
+        }
         match action {
-            Action::KeySequence { text } => self.enigo.key_sequence(&text),
-            Action::MouseMoveTo { point } => self.enigo.mouse_move_to(point.x, point.y),
-            Action::MouseMoveBy { delta } => self.enigo.mouse_move_relative(delta.x, delta.y),
-            Action::MouseDown { button } => self.enigo.mouse_down(to_enigo_button(button)),
-            Action::MouseUp { button } => self.enigo.mouse_up(to_enigo_button(button)),
-            Action::MouseClick { button } => self.enigo.mouse_click(to_enigo_button(button)),
+            Action::KeySequence { text } => self.backend.key_sequence(&text)?,
+            Action::KeyDown { key } => {
+                self.backend.key_down(key.clone())?;
+                let keys = &mut self.held_by(client).keys;
+                if !keys.contains(&key) {
+                    keys.push(key);
+                }
+            },
+            Action::KeyUp { key } => {
+                self.held_by(client).keys.retain(|k| k != &key);
+                self.backend.key_up(key)?;
+            },
+            Action::KeyClick { key } => self.backend.key_click(key)?,
+            Action::KeyChord { modifiers, key } => {
+                let held = HeldModifiers::press(&mut *self.backend, modifiers)?;
+                held.backend.key_click(key)?;
+            },
+            Action::MouseMoveTo { point } => self.backend.mouse_move_to(point)?,
+            Action::MouseMoveBy { delta } => self.backend.mouse_move_by(delta)?,
+            Action::MouseMoveToNormalized { point, display } => self.backend.mouse_move_to_normalized(point, display)?,
+            Action::MouseDown { button } => {
+                self.backend.mouse_down(button.clone())?;
+                let buttons = &mut self.held_by(client).buttons;
+                if !buttons.contains(&button) {
+                    buttons.push(button);
+                }
+            },
+            Action::MouseUp { button } => {
+                self.held_by(client).buttons.retain(|b| b != &button);
+                self.backend.mouse_up(button)?;
+            },
+            Action::MouseClick { button, count } => for _ in 0..count {
+                self.backend.mouse_click(button.clone())?;
+            },
+            Action::Scroll { delta } => self.backend.scroll(delta)?,
+            Action::SmoothScroll { delta } => self.smooth_scroll(client, delta)?,
+            Action::Batch(actions) => for action in actions {
+                self.perform(client, action)?;
+            },
+            // Delays are applied by the server before forwarding the steps,
+            // so the main thread never blocks. Should a sequence still arrive
+            // here, we perform its steps without waiting.
+            Action::Sequence { steps } => for step in steps {
+                self.perform(client, step.action)?;
+            },
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{bail, Result};
+
+    use crate::{backend::{InputBackend, RecordingBackend}, pause::Pause, protocol::{Action, Key, Modifier, MouseButton, Vec2}, server::ClientId};
+
+    use super::Controller;
+
+    /// Records like `RecordingBackend`, but fails to click keys.
+    struct FailingClicks(RecordingBackend);
+
+    impl InputBackend for FailingClicks {
+        fn key_sequence(&mut self, text: &str) -> Result<()> { self.0.key_sequence(text) }
+        fn key_down(&mut self, key: Key) -> Result<()> { self.0.key_down(key) }
+        fn key_up(&mut self, key: Key) -> Result<()> { self.0.key_up(key) }
+        fn key_click(&mut self, _key: Key) -> Result<()> { bail!("Could not click key") }
+        fn mouse_move_to(&mut self, point: Vec2<i32>) -> Result<()> { self.0.mouse_move_to(point) }
+        fn mouse_move_by(&mut self, delta: Vec2<i32>) -> Result<()> { self.0.mouse_move_by(delta) }
+        fn mouse_move_to_normalized(&mut self, point: Vec2<f64>, display: Option<usize>) -> Result<()> { self.0.mouse_move_to_normalized(point, display) }
+        fn mouse_down(&mut self, button: MouseButton) -> Result<()> { self.0.mouse_down(button) }
+        fn mouse_up(&mut self, button: MouseButton) -> Result<()> { self.0.mouse_up(button) }
+        fn scroll(&mut self, delta: Vec2<i32>) -> Result<()> { self.0.scroll(delta) }
+    }
+
+    #[test]
+    fn releases_modifiers_if_chord_fails() {
+        let backend = RecordingBackend::new();
+        let log = backend.log();
+        let mut controller = Controller::new(Box::new(FailingClicks(backend)), Pause::default());
+
+        let chord = Action::KeyChord { modifiers: vec![Modifier::Control, Modifier::Shift], key: Key::Tab };
+        assert!(controller.perform(ClientId::next(), chord).is_err());
+        assert_eq!(*log.lock().unwrap(), vec![
+            Action::KeyDown { key: Key::Control },
+            Action::KeyDown { key: Key::Shift },
+            Action::KeyUp { key: Key::Shift },
+            Action::KeyUp { key: Key::Control },
+        ]);
+    }
+
+    fn smooth_scroll(x: f64, y: f64) -> Action {
+        Action::SmoothScroll { delta: Vec2 { x, y } }
+    }
+
+    #[test]
+    fn accumulates_smooth_scrolling() {
+        let backend = RecordingBackend::new();
+        let log = backend.log();
+        let mut controller = Controller::new(Box::new(backend), Pause::default());
+        let client = ClientId::next();
+
+        for _ in 0..3 {
+            controller.perform(client, smooth_scroll(0.0, 0.4)).unwrap();
         }
+        for _ in 0..2 {
+            controller.perform(client, smooth_scroll(-0.6, 0.0)).unwrap();
+        }
+        assert_eq!(*log.lock().unwrap(), vec![
+            Action::Scroll { delta: Vec2 { x: 0, y: 1 } },
+            Action::Scroll { delta: Vec2 { x: -1, y: 0 } },
+        ]);
+    }
+
+    #[test]
+    fn keeps_scroll_remainders_per_client() {
+        let backend = RecordingBackend::new();
+        let log = backend.log();
+        let mut controller = Controller::new(Box::new(backend), Pause::default());
+        let (a, b) = (ClientId::next(), ClientId::next());
+        let scrolls = || log.lock().unwrap().len();
+
+        controller.perform(a, smooth_scroll(0.0, 0.6)).unwrap();
+        controller.perform(b, smooth_scroll(0.0, 0.6)).unwrap();
+        assert_eq!(scrolls(), 0);
+        controller.perform(a, smooth_scroll(0.0, 0.6)).unwrap();
+        assert_eq!(scrolls(), 1);
+
+        // Releasing a client drops its remainder
+        controller.release(b).unwrap();
+        controller.perform(b, smooth_scroll(0.0, 0.6)).unwrap();
+        assert_eq!(scrolls(), 1);
     }
 }
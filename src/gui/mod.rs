@@ -3,47 +3,101 @@ mod widget;
 
 use std::sync::{Arc, Mutex};
 
-use druid::{AppLauncher, WindowDesc, ExtEventSink};
+use druid::{commands::QUIT_APP, AppLauncher, WindowDesc, ExtEventSink, Target};
 use local_ip_address::local_ip;
-use tokio::{runtime::Runtime, sync::mpsc};
+use tokio::{runtime::Runtime, sync::{mpsc, watch}};
+use tracing::{info, warn};
 
-use crate::{security::Security, server::{MainThreadMessage, ServerContext}, utils::UnsafeSync, controller::Controller};
+use crate::{connections::Connections, pause::Pause, security::{DeviceStore, Security}, server::{MainThreadMessage, ServerContext}, utils::{shutdown_signal, UnsafeSync}, controller::Controller, backend::BackendConfig};
 
 use self::{state::{AppState, SecurityInfo}, widget::app_widget};
 
-fn app_launcher() -> AppLauncher<AppState> {
-    let window = WindowDesc::new(app_widget())
+fn app_launcher(connections: Connections, pause: Pause, devices: Option<Arc<DeviceStore>>) -> AppLauncher<AppState> {
+    let window = WindowDesc::new(app_widget(connections, pause, devices))
         .title("Robo")
         .window_size((640., 480.));
 
     AppLauncher::with_window(window)
 }
 
-async fn run_main_msg_loop(mut rx: mpsc::Receiver<MainThreadMessage>, event_sink: ExtEventSink) {
-    // We use `UnsafeSync` since the compiler cannot verify that we indeed always call the controller
-    // from the same (main) thread due to our use of idle callbacks.
-    let controller = Arc::new(Mutex::new(UnsafeSync::new(Controller::new())));
+// We use `UnsafeSync` since the compiler cannot verify that we indeed always call the controller
+// from the same (main) thread due to our use of idle callbacks.
+type SharedController = Arc<Mutex<UnsafeSync<Controller>>>;
 
+async fn run_main_msg_loop(mut rx: mpsc::Receiver<MainThreadMessage>, event_sink: ExtEventSink, controller: SharedController) {
     while let Some(msg) = rx.recv().await {
-        if let MainThreadMessage::DidExit = msg {
-            break;
-        };
+        let is_exit = matches!(msg, MainThreadMessage::DidExit);
         let controller = controller.clone();
         event_sink.add_idle_callback(move |state: &mut AppState| {
             match msg {
-                MainThreadMessage::Perform(action) => controller.lock().unwrap().perform(action),
+                MainThreadMessage::Perform(client, action) => if let Err(e) = controller.lock().unwrap().perform(client, action) {
+                    warn!("Could not perform action: {}", e);
+                },
+                MainThreadMessage::RequestApproval(request) => state.pending_approvals.push_back(request),
+                MainThreadMessage::CancelApproval(client) => state.pending_approvals.retain(|r| r.client.id != client),
                 MainThreadMessage::DidConnect(client) => state.connected_clients.push_back(client),
+                MainThreadMessage::DidUpdate(client) => {
+                    if let Some(c) = state.connected_clients.iter_mut().find(|c| c.id == client.id) {
+                        *c = client;
+                    }
+                },
                 MainThreadMessage::DidDisconnect(client) => {
-                    // TODO: Identify clients exactly, we currently rely on uniqueness of names
-                    // (which currently is given since we name clients after their IP + port)
-                    state.connected_clients.retain(|c| c.name != client.name);
+                    if let Err(e) = controller.lock().unwrap().release(client.id) {
+                        warn!("Could not release input held by {}: {}", client, e);
+                    }
+                    state.connected_clients.retain(|c| c.id != client.id);
+                },
+                MainThreadMessage::Release(client) => if let Err(e) = controller.lock().unwrap().release(client) {
+                    warn!("Could not release input held by {}: {}", client, e);
+                },
+                MainThreadMessage::DidPause => {
+                    // The releases of held input would be ignored while paused
+                    if let Err(e) = controller.lock().unwrap().release_all() {
+                        warn!("Could not release held input: {}", e);
+                    }
+                    state.paused = true;
+                },
+                MainThreadMessage::DidResume => state.paused = false,
+                MainThreadMessage::DidExit => if let Err(e) = controller.lock().unwrap().release_all() {
+                    warn!("Could not release held input: {}", e);
                 },
-                _ => {},
             }
         });
+        if is_exit {
+            break;
+        }
     }
 }
 
+/// Mirrors the values of a watch channel into the app state.
+fn forward_updates<T>(runtime: &Runtime, event_sink: ExtEventSink, mut updates: watch::Receiver<T>, apply: fn(&mut AppState, T))
+    where T: Clone + Send + Sync + 'static {
+    runtime.spawn(async move {
+        loop {
+            let value = updates.borrow().clone();
+            event_sink.add_idle_callback(move |state: &mut AppState| apply(state, value));
+            if updates.changed().await.is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// Releases held input and quits the app once the process is told to shut down.
+async fn quit_on_signal(event_sink: ExtEventSink, controller: SharedController) {
+    shutdown_signal().await;
+    info!("Shutting down");
+    let quit_sink = event_sink.clone();
+    event_sink.add_idle_callback(move |_: &mut AppState| {
+        if let Err(e) = controller.lock().unwrap().release_all() {
+            warn!("Could not release held input: {}", e);
+        }
+        if let Err(e) = quit_sink.submit_command(QUIT_APP, (), Target::Global) {
+            warn!("Could not quit: {}", e);
+        }
+    });
+}
+
 fn resolve_host(host: &str) -> String {
     if host == "0.0.0.0" {
         local_ip().expect("No local IP found").to_string()
@@ -52,9 +106,9 @@ fn resolve_host(host: &str) -> String {
     }
 }
 
-fn run(launcher: AppLauncher<AppState>, host: &str, port: u16, security_info: SecurityInfo) {
+fn run(launcher: AppLauncher<AppState>, host: &str, port: u16, security_info: SecurityInfo, tls_fingerprint: Option<String>) {
     let host = resolve_host(host);
-    let state = AppState::new(host, port, security_info);
+    let state = AppState::new(host, port, security_info, tls_fingerprint);
     
     launcher
         .launch(state)
@@ -62,23 +116,47 @@ fn run(launcher: AppLauncher<AppState>, host: &str, port: u16, security_info: Se
 }
 
 fn derive_security_info(security: &dyn Security) -> SecurityInfo {
-    SecurityInfo::new(security.kind().to_owned(), security.key().unwrap_or_default())
+    SecurityInfo::new(security.kind().to_owned(), security.key().unwrap_or_default(), security.salt())
 }
 
 pub fn bootstrap(
     ctx: ServerContext,
     rx: mpsc::Receiver<MainThreadMessage>,
-    runtime: Runtime
+    runtime: Runtime,
+    backend_config: BackendConfig
 ) {
     // In GUI mode druid's event loop blocks the main thread
 
-    let launcher = app_launcher();
+    let devices = ctx.security.devices();
+    let launcher = app_launcher(ctx.connections.clone(), ctx.pause.clone(), devices.clone());
     let security_info = derive_security_info(&*ctx.security);
     let event_sink = launcher.get_external_handle();
 
-    runtime.spawn(async move {
-        run_main_msg_loop(rx, event_sink).await;
-    });
+    if let Some(codes) = ctx.security.pairing_code() {
+        forward_updates(&runtime, event_sink.clone(), codes, |state, code| state.pairing_code = Some(code));
+    }
+    if let Some(devices) = devices {
+        forward_updates(&runtime, event_sink.clone(), devices.subscribe(), |state, devices| state.paired_devices = devices.into());
+    }
+
+    let controller = {
+        let backend = backend_config.create().expect("Could not create input backend");
+        Arc::new(Mutex::new(UnsafeSync::new(Controller::new(backend, ctx.pause.clone()))))
+    };
 
-    run(launcher, &ctx.host, ctx.port, security_info);
+    runtime.spawn(quit_on_signal(event_sink.clone(), controller.clone()));
+    {
+        let controller = controller.clone();
+        runtime.spawn(async move {
+            run_main_msg_loop(rx, event_sink, controller).await;
+        });
+    }
+
+    let tls_fingerprint = ctx.tls.as_ref().map(|tls| tls.fingerprint.clone());
+    run(launcher, &ctx.host, ctx.port, security_info, tls_fingerprint);
+
+    // Closing the window ends the event loop, so the idle callbacks won't release anything anymore
+    if let Err(e) = controller.lock().unwrap().release_all() {
+        warn!("Could not release held input: {}", e);
+    }
 }
@@ -3,12 +3,20 @@ use druid::{im, Data, Lens};
 use qrcodegen::{QrCode, QrCodeEcc};
 use serde::{Serialize, Deserialize};
 
-use crate::server::ClientInfo;
+use crate::{approval::ApprovalRequest, security::DeviceInfo, server::ClientInfo};
 
 #[derive(Data, Lens, Clone, Debug)]
 pub struct AppState {
     pub server_info: ServerInfo,
+    /// The current one-time code for pairing, if used.
+    pub pairing_code: Option<String>,
+    /// Whether incoming actions are ignored.
+    pub paused: bool,
     pub connected_clients: im::Vector<ClientInfo>,
+    /// New clients waiting to be approved.
+    pub pending_approvals: im::Vector<ApprovalRequest>,
+    /// The devices paired with the server, if clients pair individually.
+    pub paired_devices: im::Vector<DeviceInfo>,
 }
 
 #[derive(Data, Clone, Debug, Serialize, Deserialize)]
@@ -18,33 +26,47 @@ pub struct SecurityInfo {
     pub kind: String,
     /// The base64-encoded key.
     pub key_base64: String,
+    /// The base64-encoded salt for deriving the key, if needed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub salt_base64: Option<String>,
 }
 
 #[derive(Data, Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct ServerInfo {
     pub host: String,
     pub port: u16,
     pub security: SecurityInfo,
+    /// The hex-encoded SHA-256 fingerprint of the certificate if the
+    /// server uses TLS, which lets clients pin self-signed certificates.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_fingerprint: Option<String>,
 }
 
 impl SecurityInfo {
-    pub fn new(kind: String, key: &[u8]) -> Self {
+    pub fn new(kind: String, key: &[u8], salt: Option<&[u8]>) -> Self {
         Self {
             kind,
             key_base64: base64::encode(key),
+            salt_base64: salt.map(base64::encode),
         }
     }
 }
 
 impl AppState {
-    pub fn new(host: String, port: u16, security: SecurityInfo) -> Self {
+    pub fn new(host: String, port: u16, security: SecurityInfo, tls_fingerprint: Option<String>) -> Self {
         Self {
             server_info: ServerInfo {
                 host,
                 port,
                 security,
+                tls_fingerprint,
             },
+            pairing_code: None,
+            paused: false,
             connected_clients: im::Vector::new(),
+            pending_approvals: im::Vector::new(),
+            paired_devices: im::Vector::new(),
         }
     }
 
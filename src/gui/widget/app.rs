@@ -1,12 +1,99 @@
 use std::sync::Arc;
 
-use druid::{Widget, widget::{Flex, MainAxisAlignment, List, Label, CrossAxisAlignment}, WidgetExt, im, Color};
+use druid::{Data, Widget, widget::{Button, Flex, MainAxisAlignment, List, Label, CrossAxisAlignment}, WidgetExt, im, lens, Color};
+use tracing::warn;
 
-use crate::{gui::state::AppState, server::ClientInfo};
+use crate::{approval::{Approval, ApprovalRequest}, connections::Connections, gui::state::AppState, pause::Pause, security::{DeviceInfo, DeviceStore}, server::ClientInfo};
 
 use super::{QrWidget, NonMutWrappable};
 
-pub fn app_widget() -> impl Widget<AppState> {
+/// A list of the clients waiting for approval, whose buttons
+/// answer the request and remove it from the list.
+fn pending_approvals_widget() -> impl Widget<AppState> {
+    type Item = (im::Vector<ApprovalRequest>, ApprovalRequest);
+
+    let answer_button = |title: &str, approval: Approval| {
+        Button::new(title).on_click(move |_, (pending, request): &mut Item, _| {
+            request.answer(approval);
+            pending.retain(|r| !r.same(request));
+        })
+    };
+
+    List::new(move || {
+        Flex::row()
+            .with_child(Label::dynamic(|(_, request): &Item, _| request.client.to_string()))
+            .with_spacer(10.0)
+            .with_child(answer_button("Accept once", Approval::AcceptOnce))
+            .with_child(answer_button("Always", Approval::AcceptAlways))
+            .with_child(answer_button("Deny", Approval::Deny))
+    })
+    .lens(lens::Map::new(
+        |s: &AppState| (s.pending_approvals.clone(), s.pending_approvals.clone()),
+        |s: &mut AppState, (pending, _): (im::Vector<ApprovalRequest>, im::Vector<ApprovalRequest>)| s.pending_approvals = pending,
+    ))
+}
+
+fn paired_devices_widget(devices: Arc<DeviceStore>) -> impl Widget<AppState> {
+    List::new(move || {
+        let devices = devices.clone();
+        Flex::row()
+            .with_child(Label::dynamic(|device: &DeviceInfo, _| device.to_string()))
+            .with_spacer(10.0)
+            .with_child(Button::new("Revoke").on_click(move |_, device: &mut DeviceInfo, _| {
+                if let Err(e) = devices.revoke(&device.id) {
+                    warn!("Could not revoke {}: {}", device, e);
+                }
+            }))
+    })
+    .lens(AppState::paired_devices)
+}
+
+/// A list of the connected clients, with buttons for disconnecting them.
+fn connected_clients_widget(connections: Connections) -> impl Widget<AppState> {
+    let disconnect_all = {
+        let connections = connections.clone();
+        Button::new("Disconnect all").on_click(move |_, _: &mut AppState, _| connections.disconnect_all())
+    };
+
+    Flex::column()
+        .cross_axis_alignment(CrossAxisAlignment::Start)
+        .with_child(
+            List::new(move || {
+                let connections = connections.clone();
+                Flex::row()
+                    .with_child(Label::dynamic(|client: &ClientInfo, _| client.to_string()))
+                    .with_spacer(10.0)
+                    // The client is removed from the list once it has disconnected
+                    .with_child(Button::new("Disconnect").on_click(move |_, client: &mut ClientInfo, _| connections.disconnect(client.id)))
+            })
+            .lens(AppState::connected_clients)
+        )
+        .with_child(disconnect_all.disabled_if(|s: &AppState, _| s.connected_clients.is_empty()))
+}
+
+pub fn app_widget(connections: Connections, pause: Pause, devices: Option<Arc<DeviceStore>>) -> impl Widget<AppState> {
+    let pause_button = Button::dynamic(|s: &AppState, _| if s.paused { "Resume input" } else { "Pause input" }.to_owned())
+        .on_click(move |_, _: &mut AppState, _| pause.toggle());
+
+    let mut sidebar = Flex::column()
+        .cross_axis_alignment(CrossAxisAlignment::Start)
+        .with_child(pause_button)
+        .with_spacer(10.0)
+        .with_child(Label::dynamic(|s: &AppState, _| s.pairing_code.as_ref().map(|c| format!("Pairing code: {}", c)).unwrap_or_default()))
+        .with_spacer(10.0)
+        .with_child(pending_approvals_widget())
+        .with_child(Label::new("Connected clients:"))
+        .with_spacer(10.0)
+        .with_child(connected_clients_widget(connections));
+
+    if let Some(devices) = devices {
+        sidebar = sidebar
+            .with_spacer(20.0)
+            .with_child(Label::new("Paired devices:"))
+            .with_spacer(10.0)
+            .with_child(paired_devices_widget(devices));
+    }
+
     Flex::row()
         .main_axis_alignment(MainAxisAlignment::Center)
         .with_child(
@@ -17,14 +104,5 @@ pub fn app_widget() -> impl Widget<AppState> {
                 .background(Color::WHITE)
         )
         .with_spacer(20.)
-        .with_child(
-            Flex::column()
-                .cross_axis_alignment(CrossAxisAlignment::Start)
-                .with_child(Label::new("Connected clients:"))
-                .with_spacer(10.0)
-                .with_child(
-                    List::new(|| Label::dynamic(|(_, v): &(im::Vector<ClientInfo>, ClientInfo), _| v.name.clone()))
-                        .nonmut_wrap(|s: &AppState| (s.connected_clients.clone(), s.connected_clients.clone()))
-                )
-        )
+        .with_child(sidebar)
 }
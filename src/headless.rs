@@ -1,19 +1,157 @@
-use tokio::sync::mpsc;
+use std::{collections::VecDeque, io::{self, BufRead, Write}, sync::{Arc, Mutex}, thread};
 
-use crate::{server::MainThreadMessage, controller::Controller};
+use tokio::{runtime::Runtime, sync::mpsc};
+use tracing::{error, info, warn};
 
-fn run_main_msg_loop(mut rx: mpsc::Receiver<MainThreadMessage>) {
-    let mut controller = Controller::new();
+use crate::{approval::{Approval, ApprovalRequest}, pause::Pause, server::{ClientId, MainThreadMessage, ServerContext}, controller::Controller, backend::BackendConfig, utils::shutdown_signal};
+
+#[derive(Default)]
+struct PromptState {
+    /// The requests waiting for an answer, the first of which is shown.
+    queue: VecDeque<ApprovalRequest>,
+    /// Set once the terminal cannot be read from anymore.
+    closed: bool,
+}
+
+/// Asks on the terminal whether requesting clients may connect, one at a time.
+#[derive(Clone, Default)]
+struct Prompts {
+    state: Arc<Mutex<PromptState>>,
+}
+
+impl Prompts {
+    fn show(state: &PromptState) {
+        if let Some(request) = state.queue.front() {
+            print!("Allow {} to connect? [o]nce, [a]lways, [d]eny: ", request.client);
+            let _ = io::stdout().flush();
+        }
+    }
+
+    fn push(&self, request: ApprovalRequest) {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            request.answer(Approval::Deny);
+            return;
+        }
+        state.queue.push_back(request);
+        if state.queue.len() == 1 {
+            Self::show(&state);
+        }
+    }
+
+    fn cancel(&self, client: ClientId) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(i) = state.queue.iter().position(|r| r.client.id == client) {
+            let request = state.queue.remove(i).unwrap();
+            if i == 0 {
+                println!();
+                println!("{} is no longer waiting", request.client);
+                Self::show(&state);
+            }
+        }
+    }
+
+    /// Answers the shown request with the given line of input.
+    fn answer(&self, line: &str) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(request) = state.queue.pop_front() {
+            request.answer(match line.trim().to_lowercase().as_str() {
+                "o" | "once" => Approval::AcceptOnce,
+                "a" | "always" => Approval::AcceptAlways,
+                _ => Approval::Deny,
+            });
+            Self::show(&state);
+        }
+    }
+
+    /// Denies all current and future requests.
+    fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        for request in state.queue.drain(..) {
+            request.answer(Approval::Deny);
+        }
+    }
+
+    /// Reads answers from the terminal for as long as it is open.
+    fn read_answers(&self) {
+        for line in io::stdin().lock().lines() {
+            match line {
+                Ok(line) => self.answer(&line),
+                Err(_) => break,
+            }
+        }
+        self.close();
+    }
+}
+
+/// Toggles the pause whenever the process receives `SIGUSR1`, e.g. via `pkill -USR1 robo`.
+#[cfg(unix)]
+async fn toggle_pause_on_signal(pause: Pause) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::user_defined1()) {
+        Ok(mut signals) => while signals.recv().await.is_some() {
+            pause.toggle();
+        },
+        Err(e) => warn!("Could not listen for SIGUSR1: {}", e),
+    }
+}
+
+#[cfg(not(unix))]
+async fn toggle_pause_on_signal(_pause: Pause) {}
+
+/// Asks the main thread to release held input and exit once the process is told to shut down.
+async fn exit_on_signal(main_thread_tx: mpsc::Sender<MainThreadMessage>) {
+    shutdown_signal().await;
+    info!("Shutting down");
+    if let Err(e) = main_thread_tx.send(MainThreadMessage::DidExit).await {
+        error!("Could not send exit message to main thread: {}", e);
+    }
+}
+
+fn run_main_msg_loop(mut rx: mpsc::Receiver<MainThreadMessage>, prompts: Prompts, pause: Pause, backend_config: BackendConfig) {
+    let backend = backend_config.create().expect("Could not create input backend");
+    let mut controller = Controller::new(backend, pause);
     while let Some(msg) = rx.blocking_recv() {
         match msg {
-            MainThreadMessage::Perform(action) => controller.perform(action),
-            MainThreadMessage::DidExit => break,
+            MainThreadMessage::Perform(client, action) => if let Err(e) = controller.perform(client, action) {
+                warn!("Could not perform action: {}", e);
+            },
+            MainThreadMessage::RequestApproval(request) => prompts.push(request),
+            MainThreadMessage::CancelApproval(client) => prompts.cancel(client),
+            MainThreadMessage::DidDisconnect(client) => if let Err(e) = controller.release(client.id) {
+                warn!("Could not release input held by {}: {}", client, e);
+            },
+            MainThreadMessage::Release(client) => if let Err(e) = controller.release(client) {
+                warn!("Could not release input held by {}: {}", client, e);
+            },
+            // The releases of held input would be ignored while paused
+            MainThreadMessage::DidPause => if let Err(e) = controller.release_all() {
+                warn!("Could not release held input: {}", e);
+            },
+            MainThreadMessage::DidExit => {
+                if let Err(e) = controller.release_all() {
+                    warn!("Could not release held input: {}", e);
+                }
+                break;
+            },
             _ => {},
         }
     }
 }
 
-pub fn bootstrap(rx: mpsc::Receiver<MainThreadMessage>) {
+pub fn bootstrap(ctx: ServerContext, rx: mpsc::Receiver<MainThreadMessage>, runtime: Runtime, backend_config: BackendConfig) {
+    runtime.spawn(toggle_pause_on_signal(ctx.pause.clone()));
+    runtime.spawn(exit_on_signal(ctx.main_thread_tx));
+
+    // Answers are read on a separate thread, so other clients are not blocked meanwhile
+    let prompts = Prompts::default();
+    if ctx.allowlist.is_some() {
+        let prompts = prompts.clone();
+        thread::spawn(move || prompts.read_answers());
+    }
+
     // In headless mode we run a custom 'event loop' that handles messages from the server.
-    run_main_msg_loop(rx);
+    run_main_msg_loop(rx, prompts, ctx.pause, backend_config);
 }
@@ -1,17 +1,32 @@
+mod approval;
+mod backend;
+mod connections;
 mod headless;
 mod gui;
+mod control;
 mod controller;
+mod pause;
 mod protocol;
 mod security;
 mod server;
+mod tls;
 mod utils;
 
-use std::sync::Arc;
+use std::{path::PathBuf, sync::Arc, time::Duration};
 
-use clap::Parser;
-use security::{ChaChaPolySecurity, EmptySecurity, Security};
+use anyhow::anyhow;
+use approval::Allowlist;
+use backend::{BackendConfig, BackendKind};
+use clap::{Parser, Subcommand};
+use connections::Connections;
+use control::{ControlLock, ControlPolicy};
+use pause::Pause;
+use security::{ChaChaPolySecurity, DeviceStore, EmptySecurity, PairingSecurity, PassphraseSecurity, SharedSecurity};
+use tracing::{error, warn};
 use server::ServerContext;
+use tls::TlsConfig;
 use tokio::sync::mpsc;
+use utils::config_path;
 
 fn bootstrap_tracing() {
     let subscriber = tracing_subscriber::FmtSubscriber::new();
@@ -22,6 +37,8 @@ fn bootstrap_tracing() {
 /// Keyboard and mouse server.
 #[derive(Parser)]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
     /// The host to serve on.
     #[clap(short, long, default_value = "0.0.0.0")]
     host: String,
@@ -31,27 +48,161 @@ struct Args {
     /// Runs the server without encryption.
     #[clap(long)]
     insecure: bool,
+    /// Pairs each client using a one-time code instead of sharing a static key.
+    #[clap(long, conflicts_with = "insecure")]
+    pairing: bool,
+    /// Derives the encryption key from the given passphrase, which clients have to enter too.
+    #[clap(long, env = "ROBO_PASSPHRASE", hide_env_values = true, conflicts_with_all = &["insecure", "pairing"])]
+    passphrase: Option<String>,
+    /// The file to persist the encryption key in. Defaults to a file in the config directory.
+    #[clap(long, value_name = "FILE", conflicts_with_all = &["insecure", "pairing", "passphrase"])]
+    key_file: Option<PathBuf>,
+    /// The file to persist paired devices in. Defaults to a file in the config directory.
+    #[clap(long, value_name = "FILE")]
+    devices_file: Option<PathBuf>,
+    /// Serves over TLS (wss://) using a self-signed certificate, unless one is given.
+    #[clap(long)]
+    tls: bool,
+    /// The PEM-encoded certificate (chain) to serve over TLS with.
+    #[clap(long, value_name = "FILE", requires = "tls-key")]
+    tls_cert: Option<PathBuf>,
+    /// The PEM-encoded private key of the certificate.
+    #[clap(long, value_name = "FILE", requires = "tls-cert")]
+    tls_key: Option<PathBuf>,
+    /// Asks before accepting clients from hosts that are not on the allowlist.
+    #[clap(long)]
+    ask: bool,
+    /// The file listing hosts to accept without asking. Defaults to a file in the config directory.
+    #[clap(long, value_name = "FILE")]
+    allowlist: Option<PathBuf>,
+    /// Disconnects clients after this many invalid messages (0 for no limit).
+    #[clap(long, value_name = "COUNT", default_value_t = 10)]
+    max_client_errors: u32,
+    /// How clients share control over the input.
+    #[clap(long, value_enum, value_name = "POLICY", default_value_t = ControlPolicy::Shared)]
+    control: ControlPolicy,
+    /// How long the client holding control has to be idle before another one may take over (with `--control most-recent`).
+    #[clap(long, value_name = "MS", default_value_t = 2000)]
+    control_cooldown_ms: u64,
     /// Runs the server without a GUI.
     #[clap(long)]
     headless: bool,
+    /// The backend to inject input with.
+    #[clap(long, value_enum, default_value_t = BackendKind::Enigo)]
+    backend: BackendKind,
+    /// Only records actions instead of performing them (same as `--backend dry-run`).
+    #[clap(long)]
+    dry_run: bool,
+    /// Records actions in dry-run mode to the given JSON-lines file.
+    #[clap(long, value_name = "FILE")]
+    record: Option<PathBuf>,
+}
+
+/// Commands for managing the server instead of running it.
+#[derive(Subcommand)]
+enum Command {
+    /// Lists the devices paired with `--pairing`.
+    ListDevices,
+    /// Revokes a paired device, which then has to pair again.
+    RevokeDevice {
+        /// The id of the device, as shown by `list-devices`.
+        id: String,
+    },
+    /// Replaces the persisted encryption key with a new one, requiring clients to pair again.
+    RegenerateKey,
+}
+
+fn run_command(command: Command, devices_path: Option<PathBuf>, key_path: Option<PathBuf>) -> anyhow::Result<()> {
+    match command {
+        Command::ListDevices => {
+            let devices = DeviceStore::load(devices_path)?.list()?;
+            if devices.is_empty() {
+                println!("No paired devices");
+            }
+            for device in devices {
+                println!("{}", device);
+            }
+        },
+        Command::RevokeDevice { id } => {
+            let device = DeviceStore::load(devices_path)?.revoke(&id)?;
+            println!("Revoked {}", device);
+        },
+        Command::RegenerateKey => {
+            let key_path = key_path.ok_or_else(|| anyhow!("Could not determine config directory, please specify --key-file"))?;
+            ChaChaPolySecurity::regenerate(&key_path)?;
+            println!("Generated new key at {}, clients have to pair again", key_path.display());
+        },
+    }
+    Ok(())
 }
 
 fn main() {
     bootstrap_tracing();
 
-    let Args { host, port, insecure, headless } = Args::parse();
+    let Args { command, host, port, insecure, pairing, passphrase, key_file, devices_file, tls, tls_cert, tls_key, ask, allowlist, max_client_errors, control, control_cooldown_ms, headless, backend, dry_run, record } = Args::parse();
+
+    let devices_path = devices_file.or_else(|| config_path("devices.json"));
+    if devices_path.is_none() && (pairing || matches!(command, Some(Command::ListDevices | Command::RevokeDevice { .. }))) {
+        warn!("Could not determine config directory, paired devices will not be persisted");
+    }
+
+    let key_path = key_file.or_else(|| config_path("key"));
 
-    let security: Arc<dyn Security + Send + Sync> = if insecure {
+    if let Some(command) = command {
+        if let Err(e) = run_command(command, devices_path, key_path) {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let security: SharedSecurity = if insecure {
         Arc::new(EmptySecurity)
+    } else if pairing {
+        let devices = DeviceStore::load(devices_path).expect("Could not load paired devices");
+        Arc::new(PairingSecurity::new(Arc::new(devices)).expect("Could not set up security"))
+    } else if let Some(passphrase) = passphrase {
+        let security = match config_path("salt") {
+            Some(salt_path) => PassphraseSecurity::persistent(&passphrase, &salt_path),
+            None => {
+                warn!("Could not determine config directory, the salt will not be persisted");
+                PassphraseSecurity::with_random_salt(&passphrase)
+            },
+        };
+        Arc::new(security.expect("Could not set up security"))
+    } else if let Some(key_path) = key_path {
+        Arc::new(ChaChaPolySecurity::persistent(&key_path).expect("Could not set up security"))
     } else {
+        warn!("Could not determine config directory, the key will not be persisted");
         Arc::new(ChaChaPolySecurity::new().expect("Could not set up security"))
     };
 
+    let tls = match (tls_cert, tls_key) {
+        (Some(cert_path), Some(key_path)) => Some(TlsConfig::load(&cert_path, &key_path).expect("Could not load certificate")),
+        _ if tls => Some(TlsConfig::self_signed(config_path("tls").as_deref()).expect("Could not set up self-signed certificate")),
+        _ => None,
+    };
+
+    let allowlist = if ask {
+        let path = allowlist.or_else(|| config_path("allowlist"));
+        Some(Arc::new(Allowlist::load(path).expect("Could not load allowlist")))
+    } else {
+        None
+    };
+
+    let control = Arc::new(ControlLock::new(control, Duration::from_millis(control_cooldown_ms)));
+
+    let backend_config = BackendConfig {
+        kind: if dry_run { BackendKind::DryRun } else { backend },
+        record_path: record,
+    };
+
     let (tx, rx) = mpsc::channel(4);
-    let ctx = ServerContext { host, port, security: security.clone(), main_thread_tx: tx };
+    let ctx = ServerContext { host, port, security: security.clone(), tls, allowlist, max_client_errors, connections: Connections::default(), pause: Pause::default(), control, main_thread_tx: tx };
 
     let runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_io()
+        .enable_time()
         .worker_threads(4)
         .build()
         .expect("Could not create Tokio runtime");
@@ -64,8 +215,8 @@ fn main() {
     }
     
     if headless {
-        headless::bootstrap(rx)
+        headless::bootstrap(ctx, rx, runtime, backend_config)
     } else {
-        gui::bootstrap(ctx, rx, runtime)
+        gui::bootstrap(ctx, rx, runtime, backend_config)
     }
 }
@@ -0,0 +1,39 @@
+use std::sync::Arc;
+
+use tokio::sync::watch;
+
+/// A global switch that makes the server ignore incoming actions
+/// while keeping clients connected.
+#[derive(Clone)]
+pub struct Pause {
+    paused: Arc<watch::Sender<bool>>,
+}
+
+impl Pause {
+    pub fn new(paused: bool) -> Self {
+        let (paused, _) = watch::channel(paused);
+        Self { paused: Arc::new(paused) }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    pub fn set(&self, paused: bool) {
+        self.paused.send_replace(paused);
+    }
+
+    pub fn toggle(&self) {
+        self.set(!self.is_paused());
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.paused.subscribe()
+    }
+}
+
+impl Default for Pause {
+    fn default() -> Self {
+        Self::new(false)
+    }
+}
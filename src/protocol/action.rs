@@ -1,15 +1,30 @@
 use serde::{Serialize, Deserialize};
 
-use super::{Vec2, MouseButton};
+use super::{Vec2, Key, Modifier, MouseButton};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub enum Action {
     // Keyboard
     KeySequence { text: String },
+    KeyDown { key: Key },
+    KeyUp { key: Key },
+    KeyClick { key: Key },
+    KeyChord {
+        #[serde(default)]
+        modifiers: Vec<Modifier>,
+        key: Key,
+    },
     // Mouse
     MouseMoveTo { point: Vec2<i32> },
     MouseMoveBy { delta: Vec2<i32> },
+    /// Moves to a point given in coordinates from 0.0 to 1.0 on each axis,
+    /// relative to the given display (or the entire virtual screen if absent).
+    MouseMoveToNormalized {
+        point: Vec2<f64>,
+        #[serde(default)]
+        display: Option<usize>,
+    },
     MouseDown {
         #[serde(default)]
         button: MouseButton
@@ -20,6 +35,121 @@ pub enum Action {
     },
     MouseClick {
         #[serde(default)]
-        button: MouseButton
+        button: MouseButton,
+        /// The number of clicks, e.g. 2 for a double-click (at most 5).
+        #[serde(default = "default_click_count")]
+        count: u32,
     },
+    /// Scrolls by whole lines/columns, positive values scrolling down/right.
+    Scroll { delta: Vec2<i32> },
+    /// Scrolls by fractional lines/columns, accumulating the remainder across actions.
+    SmoothScroll { delta: Vec2<f64> },
+    // Composite
+    /// Performs the given actions in order, at once.
+    Batch(Vec<Action>),
+    /// Performs the given steps in order, each after its delay.
+    Sequence { steps: Vec<SequenceStep> },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SequenceStep {
+    /// The time to wait before performing the action.
+    #[serde(default)]
+    pub delay_ms: u64,
+    pub action: Action,
+}
+
+impl Action {
+    /// The (serialized) names of all actions.
+    pub const NAMES: &'static [&'static str] = &[
+        "keySequence",
+        "keyDown",
+        "keyUp",
+        "keyClick",
+        "keyChord",
+        "mouseMoveTo",
+        "mouseMoveBy",
+        "mouseMoveToNormalized",
+        "mouseDown",
+        "mouseUp",
+        "mouseClick",
+        "scroll",
+        "smoothScroll",
+        "batch",
+        "sequence",
+    ];
+
+    /// Whether performing this action involves waiting.
+    pub fn is_timed(&self) -> bool {
+        match self {
+            Self::Sequence { .. } => true,
+            Self::Batch(actions) => actions.iter().any(Self::is_timed),
+            _ => false,
+        }
+    }
+}
+
+fn default_click_count() -> u32 { 1 }
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::{Key, Modifier, MouseButton, Vec2};
+
+    use super::{Action, SequenceStep};
+
+    /// One action of every kind.
+    fn examples() -> Vec<Action> {
+        vec![
+            Action::KeySequence { text: "a".to_owned() },
+            Action::KeyDown { key: Key::Shift },
+            Action::KeyUp { key: Key::Shift },
+            Action::KeyClick { key: Key::Tab },
+            Action::KeyChord { modifiers: vec![Modifier::Control], key: Key::Tab },
+            Action::MouseMoveTo { point: Vec2 { x: 1, y: 2 } },
+            Action::MouseMoveBy { delta: Vec2 { x: 1, y: 2 } },
+            Action::MouseMoveToNormalized { point: Vec2 { x: 0.5, y: 0.5 }, display: None },
+            Action::MouseDown { button: MouseButton::Left },
+            Action::MouseUp { button: MouseButton::Left },
+            Action::MouseClick { button: MouseButton::Left, count: 2 },
+            Action::Scroll { delta: Vec2 { x: 0, y: 1 } },
+            Action::SmoothScroll { delta: Vec2 { x: 0.0, y: 0.5 } },
+            Action::Batch(vec![]),
+            Action::Sequence { steps: vec![SequenceStep { delay_ms: 0, action: Action::KeyClick { key: Key::Tab } }] },
+        ]
+    }
+
+    #[test]
+    fn names_match_variants() {
+        let mut tags = Vec::new();
+        for action in examples() {
+            // Adding an action fails to compile here until there is an example of it above
+            match action {
+                Action::KeySequence { .. }
+                | Action::KeyDown { .. }
+                | Action::KeyUp { .. }
+                | Action::KeyClick { .. }
+                | Action::KeyChord { .. }
+                | Action::MouseMoveTo { .. }
+                | Action::MouseMoveBy { .. }
+                | Action::MouseMoveToNormalized { .. }
+                | Action::MouseDown { .. }
+                | Action::MouseUp { .. }
+                | Action::MouseClick { .. }
+                | Action::Scroll { .. }
+                | Action::SmoothScroll { .. }
+                | Action::Batch(_)
+                | Action::Sequence { .. } => {},
+            }
+            let value = serde_json::to_value(&action).unwrap();
+            let tag = value.as_object().and_then(|o| o.keys().next()).unwrap().clone();
+            assert!(Action::NAMES.contains(&tag.as_str()), "{} is missing from Action::NAMES", tag);
+            tags.push(tag);
+        }
+
+        let mut names: Vec<_> = Action::NAMES.iter().map(|&n| n.to_owned()).collect();
+        names.sort();
+        tags.sort();
+        assert_eq!(tags, names);
+    }
 }
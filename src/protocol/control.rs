@@ -0,0 +1,62 @@
+use serde::{Serialize, Deserialize};
+
+/// A message from the client about holding exclusive control.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ControlRequest {
+    /// An optional client-chosen sequence id that the server acknowledges.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    #[serde(flatten)]
+    pub command: ControlCommand,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ControlCommand {
+    /// Asks for control, which is granted unless another client holds it.
+    RequestControl {},
+    /// Gives up control.
+    ReleaseControl {},
+}
+
+impl ControlCommand {
+    /// The (serialized) names of the commands.
+    pub const NAMES: &'static [&'static str] = &["requestControl", "releaseControl"];
+}
+
+/// Whether a client holds control, from its own perspective.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ControlStatus {
+    /// No client holds control.
+    Free,
+    /// The client holds control.
+    Yours,
+    /// Another client holds control.
+    Taken,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ControlCommand;
+
+    #[test]
+    fn names_match_variants() {
+        let mut tags = Vec::new();
+        for command in [ControlCommand::RequestControl {}, ControlCommand::ReleaseControl {}] {
+            // Adding a command fails to compile here until it is listed above
+            match command {
+                ControlCommand::RequestControl {} | ControlCommand::ReleaseControl {} => {},
+            }
+            let value = serde_json::to_value(&command).unwrap();
+            let tag = value.as_object().and_then(|o| o.keys().next()).unwrap().clone();
+            assert!(ControlCommand::NAMES.contains(&tag.as_str()), "{} is missing from ControlCommand::NAMES", tag);
+            tags.push(tag);
+        }
+
+        let mut names: Vec<_> = ControlCommand::NAMES.iter().map(|&n| n.to_owned()).collect();
+        names.sort();
+        tags.sort();
+        assert_eq!(tags, names);
+    }
+}
@@ -0,0 +1,29 @@
+use serde::{Serialize, Deserialize};
+
+use super::ServerStatus;
+
+/// The version of the protocol spoken by this server.
+pub const PROTOCOL_VERSION: u32 = 6;
+
+/// The (optional) first message sent by the client.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClientHello {
+    /// The protocol version spoken by the client.
+    pub version: u32,
+    /// A human-readable name for the client.
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+/// The server's reply to a `ClientHello`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerHello {
+    /// The protocol version spoken by the server.
+    pub version: u32,
+    /// The names of the supported actions.
+    pub actions: Vec<String>,
+    /// The kinds of security supported by the server.
+    pub security: Vec<String>,
+    /// The current state of the server.
+    pub status: ServerStatus,
+}
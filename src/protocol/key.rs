@@ -0,0 +1,46 @@
+use serde::{Serialize, Deserialize};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Key {
+    // Modifiers
+    Alt,
+    Control,
+    Shift,
+    #[serde(alias = "super", alias = "command", alias = "windows")]
+    Meta,
+    CapsLock,
+    // Editing
+    Backspace,
+    Delete,
+    Return,
+    Space,
+    Tab,
+    Escape,
+    // Navigation
+    UpArrow,
+    DownArrow,
+    LeftArrow,
+    RightArrow,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    // Function keys
+    F1,
+    F2,
+    F3,
+    F4,
+    F5,
+    F6,
+    F7,
+    F8,
+    F9,
+    F10,
+    F11,
+    F12,
+    /// A key identified by the character it produces in the current layout.
+    Unicode(char),
+    /// A platform-specific keycode.
+    Raw(u16),
+}
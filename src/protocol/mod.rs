@@ -1,7 +1,25 @@
 mod action;
+mod control;
+mod hello;
+mod key;
+mod modifier;
 mod mouse_button;
+mod pairing;
+mod request;
+mod response;
+mod salt;
+mod status;
 mod vec2;
 
 pub use action::*;
+pub use control::*;
+pub use hello::*;
+pub use key::*;
+pub use modifier::*;
 pub use mouse_button::*;
+pub use pairing::*;
+pub use request::*;
+pub use response::*;
+pub use salt::*;
+pub use status::*;
 pub use vec2::*;
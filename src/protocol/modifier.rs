@@ -0,0 +1,24 @@
+use serde::{Serialize, Deserialize};
+
+use super::Key;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Modifier {
+    Control,
+    Shift,
+    Alt,
+    #[serde(alias = "super", alias = "command", alias = "windows")]
+    Meta,
+}
+
+impl From<Modifier> for Key {
+    fn from(modifier: Modifier) -> Self {
+        match modifier {
+            Modifier::Control => Self::Control,
+            Modifier::Shift => Self::Shift,
+            Modifier::Alt => Self::Alt,
+            Modifier::Meta => Self::Meta,
+        }
+    }
+}
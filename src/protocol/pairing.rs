@@ -0,0 +1,33 @@
+use serde::{Serialize, Deserialize};
+
+/// A plaintext message from a client that wants to pair with the server.
+/// Keys and MACs are base64-encoded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PairingRequest {
+    /// Starts pairing with the client's ephemeral X25519 public key.
+    #[serde(rename_all = "camelCase")]
+    Start {
+        public_key: String,
+        /// A human-readable name for the device.
+        #[serde(default)]
+        name: Option<String>,
+    },
+    /// Proves that the client knows the pairing code.
+    Confirm { id: String, mac: String },
+}
+
+/// The server's reply to a `PairingRequest`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PairingResponse {
+    /// The server's ephemeral X25519 public key and the id of the pending pairing.
+    #[serde(rename_all = "camelCase")]
+    Challenge { id: String, public_key: String },
+    /// Completes the pairing, proving that the server knows the pairing code too.
+    /// The client prefixes its sealed frames with the (hex-decoded) device id.
+    #[serde(rename_all = "camelCase")]
+    Paired { mac: String, device_id: String },
+    /// Reports that pairing failed.
+    Failed { message: String },
+}
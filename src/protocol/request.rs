@@ -0,0 +1,13 @@
+use serde::{Serialize, Deserialize};
+
+use super::Action;
+
+/// A message from the client requesting an action.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Request {
+    /// An optional client-chosen sequence id that the server acknowledges.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    #[serde(flatten)]
+    pub action: Action,
+}
@@ -0,0 +1,57 @@
+use serde::{Serialize, Deserialize};
+
+use super::{ServerHello, ServerStatus};
+
+/// A message from the server to the client.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Response {
+    /// Completes the handshake initiated by the client.
+    Hello(ServerHello),
+    /// Acknowledges that the request with the given sequence id was accepted.
+    Ack { seq: u64 },
+    /// Reports a change in the server's state.
+    Status(ServerStatus),
+    /// Reports that a request could not be handled.
+    Error {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorCode {
+    /// The message could not be decrypted for another reason, e.g. because the client has not paired.
+    Security,
+    /// The message is too short to be a sealed message.
+    TooShort,
+    /// The message was sealed with a different key or has been tampered with.
+    AuthFailure,
+    /// The message's timestamp is too far off from the server's clock.
+    Stale,
+    /// The message has been received before.
+    Replayed,
+    /// The message is not valid UTF-8.
+    InvalidUtf8,
+    /// The message is not valid JSON.
+    InvalidJson,
+    /// The message requests an action that the server does not know.
+    UnknownAction,
+    /// The message is not a valid request.
+    InvalidRequest,
+    /// The handshake was attempted after other messages.
+    UnexpectedHello,
+    /// The server is paused and ignores actions.
+    Paused,
+    /// Another client holds control.
+    NoControl,
+    /// The client sent too many invalid messages and is disconnected.
+    TooManyErrors,
+    /// Too many actions of the client are waiting to be performed.
+    Busy,
+    /// The server failed to process the request.
+    Internal,
+}
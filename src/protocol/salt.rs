@@ -0,0 +1,17 @@
+use serde::{Serialize, Deserialize};
+
+/// A plaintext message from a client asking for the salt of a passphrase-derived
+/// key. This is for clients that cannot read the `robo-salt` header of the
+/// websocket handshake response, e.g. browsers.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SaltRequest {
+    GetSalt {},
+}
+
+/// The server's reply to a `SaltRequest`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SaltResponse {
+    /// The base64-encoded salt, if the key is derived from a passphrase.
+    pub salt: Option<String>,
+}
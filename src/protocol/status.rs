@@ -0,0 +1,13 @@
+use serde::{Serialize, Deserialize};
+
+use super::ControlStatus;
+
+/// The state of the server, which is sent to clients whenever it changes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerStatus {
+    /// Whether the server currently ignores actions.
+    pub paused: bool,
+    /// Whether the client holds control, if control is exclusive.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub control: Option<ControlStatus>,
+}
@@ -1,22 +1,61 @@
-use anyhow::{anyhow, Result};
+use std::{path::Path, sync::{Arc, Mutex}};
+
+use anyhow::{anyhow, bail, Result};
 use ring::{aead::{CHACHA20_POLY1305, NONCE_LEN, LessSafeKey, UnboundKey, Nonce, Aad}, rand::{SystemRandom, SecureRandom}};
+use tracing::info;
+
+use super::{now_millis, read_key, write_key, OpenError, ReplayWindow, Security};
 
-use super::Security;
+/// The length of the timestamp prefixed to the plaintext.
+const TIMESTAMP_LEN: usize = 8;
 
 /// A security implementation that uses ChaCha20-Poly1305
 /// for symmetric, authenticated encryption.
+///
+/// To protect against replays, each plaintext is prefixed with a big-endian
+/// 64-bit timestamp (in milliseconds since the Unix epoch) before sealing.
+/// Opened messages are only accepted if this timestamp is recent and their
+/// nonce has not been seen before.
 #[derive(Clone, Debug)]
 pub struct ChaChaPolySecurity {
     rng: SystemRandom,
     key: Vec<u8>,
+    replay_window: Arc<Mutex<ReplayWindow>>,
 }
 
 impl ChaChaPolySecurity {
+    /// Creates a security instance with a freshly generated key.
     pub fn new() -> Result<Self> {
         let mut key = vec![0u8; CHACHA20_POLY1305.key_len()];
         let rng = SystemRandom::new();
         rng.fill(&mut key).map_err(|_| anyhow!("Could not generate key"))?;
-        Ok(Self { rng, key })
+        Ok(Self { rng, key, replay_window: Default::default() })
+    }
+
+    /// Creates a security instance with the given key.
+    pub fn with_key(key: Vec<u8>) -> Result<Self> {
+        if key.len() != CHACHA20_POLY1305.key_len() {
+            bail!("Key has invalid length {} (expected {})", key.len(), CHACHA20_POLY1305.key_len());
+        }
+        Ok(Self { rng: SystemRandom::new(), key, replay_window: Default::default() })
+    }
+
+    /// Creates a security instance with the key persisted at the given path,
+    /// generating (and persisting) a new one if there is none.
+    pub fn persistent(path: &Path) -> Result<Self> {
+        if let Some(key) = read_key(path)? {
+            return Self::with_key(key);
+        }
+        let security = Self::regenerate(path)?;
+        info!("Generated new key at {}", path.display());
+        Ok(security)
+    }
+
+    /// Generates a new key and persists it at the given path, replacing the existing one.
+    pub fn regenerate(path: &Path) -> Result<Self> {
+        let security = Self::new()?;
+        write_key(path, &security.key)?;
+        Ok(security)
     }
 
     fn less_safe_key(&self) -> Result<LessSafeKey> {
@@ -37,7 +76,8 @@ impl Security for ChaChaPolySecurity {
         // Safe to use here since we use random nonces (see https://github.com/briansmith/ring/issues/899#issuecomment-534346205)
         let key = self.less_safe_key()?;
 
-        let mut buffer = plaintext.to_vec();
+        let mut buffer = now_millis().to_be_bytes().to_vec();
+        buffer.extend_from_slice(plaintext);
         key.seal_in_place_append_tag(
             Nonce::assume_unique_for_key(nonce),
             Aad::empty(),
@@ -49,17 +89,66 @@ impl Security for ChaChaPolySecurity {
     }
 
     fn open(&self, sealed_box: &[u8]) -> Result<Vec<u8>> {
-        let nonce: [u8; NONCE_LEN] = sealed_box[..NONCE_LEN].try_into().unwrap();
+        if sealed_box.len() < NONCE_LEN + TIMESTAMP_LEN + CHACHA20_POLY1305.tag_len() {
+            bail!(OpenError::TooShort);
+        }
+        let (nonce, ciphertext) = sealed_box.split_at(NONCE_LEN);
+        let nonce: [u8; NONCE_LEN] = nonce.try_into()?;
 
         let key = self.less_safe_key()?;
 
-        let mut buffer = sealed_box[NONCE_LEN..].to_vec();
+        let mut buffer = ciphertext.to_vec();
         let plaintext = key.open_in_place(
             Nonce::assume_unique_for_key(nonce),
             Aad::empty(),
             &mut buffer
-        ).map_err(|_| anyhow!("Could not open message"))?;
+        ).map_err(|_| OpenError::AuthFailure)?;
+
+        // The length check above guarantees that the timestamp is there
+        let (timestamp, plaintext) = plaintext.split_at(TIMESTAMP_LEN);
+        let timestamp = u64::from_be_bytes(timestamp.try_into()?);
+        self.replay_window.lock().unwrap().accept(nonce, timestamp, now_millis())?;
 
         Ok(plaintext.to_vec())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::security::{OpenError, Security};
+
+    use super::ChaChaPolySecurity;
+
+    #[test]
+    fn opens_sealed_messages() {
+        let security = ChaChaPolySecurity::new().unwrap();
+        let sealed = security.seal(b"hello").unwrap();
+        assert_eq!(security.open(&sealed).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn rejects_replayed_messages() {
+        let security = ChaChaPolySecurity::new().unwrap();
+        let sealed = security.seal(b"hello").unwrap();
+        security.open(&sealed).unwrap();
+        assert!(security.open(&sealed).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_messages() {
+        let security = ChaChaPolySecurity::new().unwrap();
+        let sealed = security.seal(b"").unwrap();
+        for len in 0..sealed.len() {
+            let error = security.open(&sealed[..len]).unwrap_err();
+            assert!(error.downcast_ref::<OpenError>().is_some(), "Unexpected error {}", error);
+        }
+    }
+
+    #[test]
+    fn rejects_tampered_messages() {
+        let security = ChaChaPolySecurity::new().unwrap();
+        let mut sealed = security.seal(b"hello").unwrap();
+        *sealed.last_mut().unwrap() ^= 1;
+        assert!(security.open(&sealed).is_err());
+    }
+}
@@ -0,0 +1,283 @@
+use std::{collections::HashMap, fmt, fs, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::SystemTime};
+
+use anyhow::{anyhow, bail, Context, Result};
+use druid::Data;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Serialize, Deserialize};
+use tokio::sync::watch;
+
+use crate::utils::hex;
+
+use super::{write_private, ChaChaPolySecurity, OpenError, Security};
+
+/// The length of the device id that paired clients prefix their frames with.
+pub const DEVICE_ID_LEN: usize = 8;
+
+/// A client that has paired with the server.
+#[derive(Debug, Clone, PartialEq, Data, Serialize, Deserialize)]
+pub struct DeviceInfo {
+    /// The hex-encoded device id.
+    pub id: String,
+    /// The human-readable name supplied by the client when pairing, if any.
+    pub name: Option<String>,
+}
+
+impl fmt::Display for DeviceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{} {}", self.id, name),
+            None => write!(f, "{}", self.id),
+        }
+    }
+}
+
+/// A device as persisted on disk.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StoredDevice {
+    #[serde(flatten)]
+    info: DeviceInfo,
+    key_base64: String,
+}
+
+struct Device {
+    info: DeviceInfo,
+    security: ChaChaPolySecurity,
+}
+
+#[derive(Default)]
+struct StoreState {
+    devices: HashMap<String, Device>,
+    /// The modification time of the file when it was last read or written.
+    modified: Option<SystemTime>,
+}
+
+/// The clients that have paired with the server, each with its own key.
+///
+/// The store is persisted to a JSON file (if a path is given). Since devices
+/// may also be revoked from the command line while the server is running,
+/// the file is re-read whenever it has been modified.
+pub struct DeviceStore {
+    path: Option<PathBuf>,
+    rng: SystemRandom,
+    state: Mutex<StoreState>,
+    updates: watch::Sender<Vec<DeviceInfo>>,
+}
+
+impl DeviceStore {
+    /// Loads the devices stored at the given path, if any.
+    pub fn load(path: Option<PathBuf>) -> Result<Self> {
+        let (updates, _) = watch::channel(Vec::new());
+        let store = Self { path, rng: SystemRandom::new(), state: Default::default(), updates };
+        store.refresh(&mut store.state.lock().unwrap())?;
+        Ok(store)
+    }
+
+    /// The paired devices.
+    pub fn list(&self) -> Result<Vec<DeviceInfo>> {
+        let mut state = self.state.lock().unwrap();
+        self.refresh(&mut state)?;
+        Ok(infos(&state))
+    }
+
+    /// Notifies about changes to the paired devices.
+    pub fn subscribe(&self) -> watch::Receiver<Vec<DeviceInfo>> {
+        self.updates.subscribe()
+    }
+
+    /// Whether a device with the given (hex-encoded) id is paired.
+    pub fn contains(&self, id: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        self.refresh(&mut state).is_ok() && state.devices.contains_key(id)
+    }
+
+    /// Stores a newly paired device with the given key.
+    pub fn add(&self, name: Option<String>, key: Vec<u8>) -> Result<DeviceInfo> {
+        let mut id = [0u8; DEVICE_ID_LEN];
+        self.rng.fill(&mut id).map_err(|_| anyhow!("Could not generate device id"))?;
+        let info = DeviceInfo { id: hex(&id), name };
+        let security = ChaChaPolySecurity::with_key(key)?;
+
+        let mut state = self.state.lock().unwrap();
+        self.refresh(&mut state)?;
+        state.devices.insert(info.id.clone(), Device { info: info.clone(), security });
+        self.save(&mut state)?;
+        Ok(info)
+    }
+
+    /// Removes the device with the given id, which then has to pair again.
+    pub fn revoke(&self, id: &str) -> Result<DeviceInfo> {
+        let mut state = self.state.lock().unwrap();
+        self.refresh(&mut state)?;
+        let device = state.devices.remove(id).ok_or_else(|| anyhow!("No paired device with id {}", id))?;
+        self.save(&mut state)?;
+        Ok(device.info)
+    }
+
+    /// Runs the given function with the security of the device with the given id.
+    fn with_security<R>(&self, id: &str, f: impl FnOnce(&ChaChaPolySecurity) -> Result<R>) -> Result<R> {
+        let mut state = self.state.lock().unwrap();
+        self.refresh(&mut state)?;
+        let device = state.devices.get(id).ok_or_else(|| anyhow!("Device {} is not paired (anymore)", id))?;
+        f(&device.security)
+    }
+
+    /// Re-reads the devices if the file has been modified since.
+    fn refresh(&self, state: &mut StoreState) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let modified = modified(path);
+        if modified == state.modified {
+            return Ok(());
+        }
+
+        let stored: Vec<StoredDevice> = match modified {
+            Some(_) => {
+                let raw = fs::read_to_string(path).with_context(|| format!("Could not read devices from {}", path.display()))?;
+                serde_json::from_str(&raw).with_context(|| format!("Could not decode devices in {}", path.display()))?
+            },
+            None => Vec::new(),
+        };
+
+        let mut devices = HashMap::new();
+        for StoredDevice { info, key_base64 } in stored {
+            let key = base64::decode(key_base64)?;
+            // Keep the existing security where possible, since it remembers recent nonces
+            let security = match state.devices.remove(&info.id) {
+                Some(device) if device.security.key() == Some(key.as_slice()) => device.security,
+                _ => ChaChaPolySecurity::with_key(key)?,
+            };
+            devices.insert(info.id.clone(), Device { info, security });
+        }
+
+        state.devices = devices;
+        state.modified = modified;
+        self.updates.send_replace(infos(state));
+        Ok(())
+    }
+
+    fn save(&self, state: &mut StoreState) -> Result<()> {
+        if let Some(path) = &self.path {
+            let stored: Vec<_> = state.devices.values().map(|device| StoredDevice {
+                info: device.info.clone(),
+                key_base64: base64::encode(device.security.key().unwrap_or_default()),
+            }).collect();
+            write_private(path, &serde_json::to_vec_pretty(&stored)?)
+                .with_context(|| format!("Could not write devices to {}", path.display()))?;
+            state.modified = modified(path);
+        }
+        self.updates.send_replace(infos(state));
+        Ok(())
+    }
+}
+
+/// A security implementation for a single paired device. Sealed frames
+/// are prefixed with the raw device id, so the server can look up the key.
+pub struct DeviceSecurity {
+    store: Arc<DeviceStore>,
+    id: [u8; DEVICE_ID_LEN],
+}
+
+impl DeviceSecurity {
+    /// Creates a security instance for the device with the given (hex-encoded) id.
+    pub fn new(store: Arc<DeviceStore>, id: &str) -> Result<Self> {
+        let id = unhex(id).ok_or_else(|| anyhow!("Invalid device id {}", id))?;
+        Ok(Self { store, id })
+    }
+
+    /// Creates a security instance for the device that sealed the given
+    /// frame, if the frame has a device id prefix and the device is paired.
+    pub fn for_frame(store: &Arc<DeviceStore>, frame: &[u8]) -> Option<Self> {
+        let id: [u8; DEVICE_ID_LEN] = frame.get(..DEVICE_ID_LEN)?.try_into().ok()?;
+        store.contains(&hex(&id)).then(|| Self { store: store.clone(), id })
+    }
+}
+
+impl Security for DeviceSecurity {
+    fn kind(&self) -> &'static str { "pairing" }
+
+    fn key(&self) -> Option<&[u8]> { None }
+
+    fn seal(&self, value: &[u8]) -> Result<Vec<u8>> {
+        let sealed = self.store.with_security(&hex(&self.id), |security| security.seal(value))?;
+        Ok(self.id.iter().copied().chain(sealed).collect())
+    }
+
+    fn open(&self, frame: &[u8]) -> Result<Vec<u8>> {
+        if frame.len() < DEVICE_ID_LEN {
+            bail!(OpenError::TooShort);
+        }
+        if frame[..DEVICE_ID_LEN] != self.id {
+            bail!("Frame is not from device {}", hex(&self.id));
+        }
+        self.store.with_security(&hex(&self.id), |security| security.open(&frame[DEVICE_ID_LEN..]))
+    }
+
+    fn device_id(&self) -> Option<String> {
+        Some(hex(&self.id))
+    }
+}
+
+fn infos(state: &StoreState) -> Vec<DeviceInfo> {
+    let mut infos: Vec<_> = state.devices.values().map(|device| device.info.clone()).collect();
+    infos.sort_by(|a, b| a.id.cmp(&b.id));
+    infos
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+fn unhex(hex: &str) -> Option<[u8; DEVICE_ID_LEN]> {
+    if hex.len() != 2 * DEVICE_ID_LEN {
+        return None;
+    }
+    let mut id = [0u8; DEVICE_ID_LEN];
+    for (i, byte) in id.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process, sync::Arc};
+
+    use crate::security::{ChaChaPolySecurity, Security};
+
+    use super::{unhex, DeviceSecurity, DeviceStore, DEVICE_ID_LEN};
+
+    #[test]
+    fn opens_frames_until_revoked() {
+        let path = env::temp_dir().join(format!("robo-devices-{}.json", process::id()));
+        let _ = fs::remove_file(&path);
+
+        let store = Arc::new(DeviceStore::load(Some(path.clone())).unwrap());
+        let device = store.add(Some("Phone".to_owned()), vec![7; 32]).unwrap();
+
+        let client = ChaChaPolySecurity::with_key(vec![7; 32]).unwrap();
+        let seal = |value: &[u8]| {
+            let mut frame = unhex(&device.id).unwrap().to_vec();
+            frame.extend(client.seal(value).unwrap());
+            frame
+        };
+
+        let frame = seal(b"hello");
+        let session = DeviceSecurity::for_frame(&store, &frame).unwrap();
+        assert_eq!(session.open(&frame).unwrap(), b"hello");
+        assert_eq!(client.open(&session.seal(b"hi").unwrap()[DEVICE_ID_LEN..]).unwrap(), b"hi");
+
+        // Revoking through another instance (e.g. from the command line) also works
+        let other = DeviceStore::load(Some(path.clone())).unwrap();
+        assert_eq!(other.list().unwrap(), vec![device.clone()]);
+        other.revoke(&device.id).unwrap();
+
+        let frame = seal(b"hello");
+        assert!(session.open(&frame).is_err());
+        assert!(DeviceSecurity::for_frame(&store, &frame).is_none());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,29 @@
+use std::fmt;
+
+use super::MAX_MESSAGE_AGE;
+
+/// The reasons a sealed frame can be rejected for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenError {
+    /// The frame is too short to hold a nonce, a timestamp and a tag.
+    TooShort,
+    /// The frame was sealed with a different key or has been tampered with.
+    AuthFailure,
+    /// The frame's timestamp is too far off from the local clock.
+    Stale,
+    /// The frame has been received before.
+    Replayed,
+}
+
+impl fmt::Display for OpenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooShort => write!(f, "Message is too short"),
+            Self::AuthFailure => write!(f, "Could not open message"),
+            Self::Stale => write!(f, "Message timestamp is off by more than {}s, either it was replayed or the clocks are out of sync", MAX_MESSAGE_AGE.as_secs()),
+            Self::Replayed => write!(f, "Message was replayed"),
+        }
+    }
+}
+
+impl std::error::Error for OpenError {}
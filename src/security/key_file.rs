@@ -0,0 +1,44 @@
+use std::{fs, io::Write, path::Path};
+
+use anyhow::{Context, Result};
+
+/// Reads a base64-encoded key from the given file, if it exists.
+pub fn read_key(path: &Path) -> Result<Option<Vec<u8>>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let encoded = fs::read_to_string(path).with_context(|| format!("Could not read key from {}", path.display()))?;
+    let key = base64::decode(encoded.trim()).with_context(|| format!("Could not decode key in {}", path.display()))?;
+    Ok(Some(key))
+}
+
+/// Writes a base64-encoded key to the given file, readable only by the owner.
+pub fn write_key(path: &Path, key: &[u8]) -> Result<()> {
+    write_private(path, format!("{}\n", base64::encode(key)).as_bytes())
+        .with_context(|| format!("Could not write key to {}", path.display()))
+}
+
+/// Writes the given contents to a file that is readable only by the owner.
+pub fn write_private(path: &Path, contents: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path)?;
+    // The mode above only applies to newly created files
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(contents)?;
+    Ok(())
+}
@@ -1,10 +1,30 @@
 mod empty;
 mod chachapoly;
+mod devices;
+mod error;
+mod key_file;
+mod pairing;
+mod passphrase;
+mod replay;
 
 pub use empty::*;
 pub use chachapoly::*;
+pub use devices::*;
+pub use error::*;
+pub use key_file::*;
+pub use pairing::*;
+pub use passphrase::*;
+pub use replay::*;
 
-use anyhow::Result;
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use tokio::sync::watch;
+
+use crate::protocol::{PairingRequest, PairingResponse};
+
+/// A security implementation that can be shared across connections.
+pub type SharedSecurity = Arc<dyn Security + Send + Sync>;
 
 /// An optional layer of encryption.
 pub trait Security {
@@ -14,9 +34,34 @@ pub trait Security {
     /// The key for encryption that is shared with the client, if used.
     fn key(&self) -> Option<&[u8]>;
 
+    /// The salt the client needs to derive the key, if any. It is advertised
+    /// in the websocket handshake.
+    fn salt(&self) -> Option<&[u8]> { None }
+
     /// Encrypts a message (if needed).
     fn seal(&self, value: &[u8]) -> Result<Vec<u8>>;
 
     /// Decrypts a message (if needed).
     fn open(&self, value: &[u8]) -> Result<Vec<u8>>;
+
+    /// Handles a pairing request from a client. Returns the response and,
+    /// once pairing has completed, the security to use for the rest of the
+    /// connection.
+    fn pair(&self, _request: PairingRequest) -> Result<(PairingResponse, Option<SharedSecurity>)> {
+        bail!("Security {} does not support pairing", self.kind())
+    }
+
+    /// The current one-time pairing code, if pairing is supported.
+    fn pairing_code(&self) -> Option<watch::Receiver<String>> { None }
+
+    /// Picks the security for a connection based on a frame received on it
+    /// before pairing. Implementations with per-client keys use this to find
+    /// the client's key, by default all connections share this security.
+    fn session(&self, _frame: &[u8]) -> Option<SharedSecurity> { None }
+
+    /// The store of paired devices, if clients are paired individually.
+    fn devices(&self) -> Option<Arc<DeviceStore>> { None }
+
+    /// The (hex-encoded) id of the paired device this security is for, if any.
+    fn device_id(&self) -> Option<String> { None }
 }
@@ -0,0 +1,289 @@
+use std::{collections::HashMap, num::NonZeroU32, sync::{Arc, Mutex, MutexGuard}, time::{Duration, Instant}};
+
+use anyhow::{anyhow, bail, Result};
+use ring::{agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, X25519}, hmac, pbkdf2, rand::{SecureRandom, SystemRandom}};
+use tokio::sync::watch;
+
+use crate::protocol::{PairingRequest, PairingResponse};
+
+use super::{DeviceSecurity, DeviceStore, Security, SharedSecurity};
+
+/// The characters pairing codes are made of (no 0/O or 1/I to avoid confusion).
+const CODE_ALPHABET: &[u8; 32] = b"23456789ABCDEFGHJKLMNPQRSTUVWXYZ";
+/// The number of characters in a pairing code.
+const CODE_LEN: usize = 8;
+/// The number of PBKDF2 iterations the code is stretched with.
+const CODE_ITERATIONS: u32 = 100_000;
+/// How long a client has to confirm a started pairing.
+const PAIRING_TIMEOUT: Duration = Duration::from_secs(60);
+/// The maximum number of pairings that may be pending at once.
+const MAX_PENDING: usize = 16;
+
+/// A pairing that has been started, but not yet confirmed by the client.
+struct PendingPairing {
+    name: Option<String>,
+    transcript: Vec<u8>,
+    session_key: Vec<u8>,
+    confirm_key: hmac::Key,
+    started: Instant,
+}
+
+/// A security implementation where clients pair with the server using
+/// an ephemeral X25519 key exchange, authenticated by a one-time code
+/// that is displayed on the server. Each paired client is stored as a
+/// device with its own key, which is then used with ChaCha20-Poly1305.
+///
+/// Pairing takes two round trips:
+///
+/// 1. The client sends its public key (`start`), the server replies with
+///    its own public key and an id for the pairing (`challenge`).
+/// 2. Both sides derive keys from the shared secret, the public keys and
+///    the code. The client proves that it knows the code by sending a MAC
+///    over the public keys (`confirm`), the server verifies it and proves
+///    knowledge of the code in turn (`paired`), along with the id of the
+///    new device. From then on, the client prefixes its frames with this id
+///    (see `DeviceSecurity`), also when reconnecting later.
+///
+/// Since the code is stretched with PBKDF2 and salted with both public keys,
+/// it cannot be brute-forced from a recorded exchange in practice. Online
+/// guessing is limited by rotating the code after every confirmation,
+/// regardless of whether it succeeded, and by the number of pending pairings.
+/// The server additionally allows only one pending pairing per connection.
+pub struct PairingSecurity {
+    rng: SystemRandom,
+    code: watch::Sender<String>,
+    pending: Mutex<HashMap<String, PendingPairing>>,
+    devices: Arc<DeviceStore>,
+}
+
+impl PairingSecurity {
+    pub fn new(devices: Arc<DeviceStore>) -> Result<Self> {
+        let rng = SystemRandom::new();
+        let (code, _) = watch::channel(generate_code(&rng)?);
+        Ok(Self { rng, code, pending: Mutex::new(HashMap::new()), devices })
+    }
+
+    fn rotate_code(&self) -> Result<()> {
+        self.code.send_replace(generate_code(&self.rng)?);
+        Ok(())
+    }
+
+    /// Locks the pending pairings, failing if there are too many of them already.
+    fn check_pending(&self) -> Result<MutexGuard<'_, HashMap<String, PendingPairing>>> {
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, p| p.started.elapsed() < PAIRING_TIMEOUT);
+        if pending.len() >= MAX_PENDING {
+            bail!("Too many pending pairings");
+        }
+        Ok(pending)
+    }
+
+    fn start(&self, client_public_key: &str, name: Option<String>) -> Result<PairingResponse> {
+        // Deriving the keys is expensive, so we check this before
+        drop(self.check_pending()?);
+
+        let client_public_key = base64::decode(client_public_key)?;
+        let private_key = EphemeralPrivateKey::generate(&X25519, &self.rng).map_err(|_| anyhow!("Could not generate key pair"))?;
+        let public_key = private_key.compute_public_key().map_err(|_| anyhow!("Could not compute public key"))?;
+
+        let transcript = transcript(&client_public_key, public_key.as_ref());
+        let code = normalize_code(&self.code.borrow());
+        let (session_key, confirm_key) = agreement::agree_ephemeral(
+            private_key,
+            &UnparsedPublicKey::new(&X25519, &client_public_key),
+            anyhow!("Invalid public key"),
+            |shared_secret| Ok(derive_keys(&code, shared_secret, &transcript)),
+        )?;
+
+        let mut id = [0u8; 16];
+        self.rng.fill(&mut id).map_err(|_| anyhow!("Could not generate pairing id"))?;
+        let id = base64::encode(id);
+
+        // Other pairings may have started while we derived the keys
+        let mut pending = self.check_pending()?;
+        pending.insert(id.clone(), PendingPairing { name, transcript, session_key, confirm_key, started: Instant::now() });
+
+        Ok(PairingResponse::Challenge { id, public_key: base64::encode(public_key.as_ref()) })
+    }
+
+    fn confirm(&self, id: &str, mac: &str) -> Result<(PairingResponse, Option<SharedSecurity>)> {
+        let pairing = {
+            let mut pending = self.pending.lock().unwrap();
+            let pairing = pending.remove(id).filter(|p| p.started.elapsed() < PAIRING_TIMEOUT);
+            if pairing.is_some() {
+                // Every pairing gets a single guess. Other pairings keep the
+                // code they started with, so a failed guess doesn't abort them.
+                self.rotate_code()?;
+            }
+            pairing.ok_or_else(|| anyhow!("No pending pairing with this id"))?
+        };
+
+        let mac = base64::decode(mac)?;
+        hmac::verify(&pairing.confirm_key, &tagged(b"client", &pairing.transcript), &mac)
+            .map_err(|_| anyhow!("Invalid pairing code"))?;
+
+        let device = self.devices.add(pairing.name, pairing.session_key)?;
+        let server_mac = hmac::sign(&pairing.confirm_key, &tagged(b"server", &pairing.transcript));
+        let session: SharedSecurity = Arc::new(DeviceSecurity::new(self.devices.clone(), &device.id)?);
+        Ok((PairingResponse::Paired { mac: base64::encode(server_mac.as_ref()), device_id: device.id }, Some(session)))
+    }
+}
+
+impl Security for PairingSecurity {
+    fn kind(&self) -> &'static str { "pairing" }
+
+    fn key(&self) -> Option<&[u8]> { None }
+
+    fn seal(&self, _value: &[u8]) -> Result<Vec<u8>> {
+        bail!("Client has not paired yet")
+    }
+
+    fn open(&self, _value: &[u8]) -> Result<Vec<u8>> {
+        bail!("Client has not paired (or has been revoked)")
+    }
+
+    fn pair(&self, request: PairingRequest) -> Result<(PairingResponse, Option<SharedSecurity>)> {
+        match request {
+            PairingRequest::Start { public_key, name } => Ok((self.start(&public_key, name)?, None)),
+            PairingRequest::Confirm { id, mac } => self.confirm(&id, &mac),
+        }
+    }
+
+    fn pairing_code(&self) -> Option<watch::Receiver<String>> {
+        Some(self.code.subscribe())
+    }
+
+    fn session(&self, frame: &[u8]) -> Option<SharedSecurity> {
+        DeviceSecurity::for_frame(&self.devices, frame).map(|s| Arc::new(s) as SharedSecurity)
+    }
+
+    fn devices(&self) -> Option<Arc<DeviceStore>> {
+        Some(self.devices.clone())
+    }
+}
+
+/// Generates a random code, formatted as two groups of four characters.
+fn generate_code(rng: &SystemRandom) -> Result<String> {
+    let mut bytes = [0u8; CODE_LEN];
+    rng.fill(&mut bytes).map_err(|_| anyhow!("Could not generate pairing code"))?;
+    let (first, second) = bytes.split_at(CODE_LEN / 2);
+    let group = |bytes: &[u8]| bytes.iter().map(|&b| CODE_ALPHABET[(b % 32) as usize] as char).collect::<String>();
+    Ok(format!("{}-{}", group(first), group(second)))
+}
+
+/// The code as used in the key derivation, i.e. in upper case without separators.
+fn normalize_code(code: &str) -> String {
+    code.chars().filter(char::is_ascii_alphanumeric).map(|c| c.to_ascii_uppercase()).collect()
+}
+
+/// The data both sides authenticate, binding the keys to this exchange.
+fn transcript(client_public_key: &[u8], server_public_key: &[u8]) -> Vec<u8> {
+    [b"robo pairing".as_slice(), client_public_key, server_public_key].concat()
+}
+
+fn tagged(tag: &[u8], transcript: &[u8]) -> Vec<u8> {
+    [tag, transcript].concat()
+}
+
+/// Derives the session key and the key for the confirmation MACs from
+/// the shared secret and the (stretched) code, HKDF-style.
+fn derive_keys(code: &str, shared_secret: &[u8], transcript: &[u8]) -> (Vec<u8>, hmac::Key) {
+    let mut stretched_code = [0u8; 32];
+    let iterations = NonZeroU32::new(CODE_ITERATIONS).unwrap();
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, transcript, code.as_bytes(), &mut stretched_code);
+
+    let prk = hmac::Key::new(hmac::HMAC_SHA256, hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, &stretched_code), shared_secret).as_ref());
+    let session_key = hmac::sign(&prk, b"robo session key").as_ref().to_vec();
+    let confirm_key = hmac::Key::new(hmac::HMAC_SHA256, hmac::sign(&prk, b"robo confirm key").as_ref());
+    (session_key, confirm_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use ring::{agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, X25519}, hmac, rand::SystemRandom};
+
+    use std::sync::Arc;
+
+    use crate::{protocol::{PairingRequest, PairingResponse}, security::{Security, ChaChaPolySecurity, DeviceStore, DEVICE_ID_LEN}};
+
+    use super::{derive_keys, normalize_code, tagged, transcript, PairingSecurity};
+
+    /// The client side of a started pairing.
+    struct ClientPairing {
+        id: String,
+        transcript: Vec<u8>,
+        session_key: Vec<u8>,
+        confirm_key: hmac::Key,
+    }
+
+    /// Performs the client side of the pairing with the given code,
+    /// returning the client's session security if the server accepted it.
+    fn pair(server: &PairingSecurity, code: &str) -> Result<ChaChaPolySecurity> {
+        let pairing = start(server, code)?;
+        confirm(server, pairing)
+    }
+
+    fn start(server: &PairingSecurity, code: &str) -> Result<ClientPairing> {
+        let rng = SystemRandom::new();
+        let private_key = EphemeralPrivateKey::generate(&X25519, &rng).unwrap();
+        let public_key = private_key.compute_public_key().unwrap();
+
+        let start = PairingRequest::Start { public_key: base64::encode(public_key.as_ref()), name: None };
+        let (id, server_public_key) = match server.pair(start)?.0 {
+            PairingResponse::Challenge { id, public_key } => (id, base64::decode(public_key)?),
+            response => panic!("Unexpected response {:?}", response),
+        };
+
+        let transcript = transcript(public_key.as_ref(), &server_public_key);
+        let (session_key, confirm_key) = agreement::agree_ephemeral(
+            private_key,
+            &UnparsedPublicKey::new(&X25519, &server_public_key),
+            (),
+            |shared_secret| Ok(derive_keys(&normalize_code(code), shared_secret, &transcript)),
+        ).unwrap();
+
+        Ok(ClientPairing { id, transcript, session_key, confirm_key })
+    }
+
+    fn confirm(server: &PairingSecurity, pairing: ClientPairing) -> Result<ChaChaPolySecurity> {
+        let ClientPairing { id, transcript, session_key, confirm_key } = pairing;
+        let mac = hmac::sign(&confirm_key, &tagged(b"client", &transcript));
+        let confirm = PairingRequest::Confirm { id, mac: base64::encode(mac.as_ref()) };
+        let (server_mac, session) = match server.pair(confirm)? {
+            (PairingResponse::Paired { mac, .. }, Some(session)) => (base64::decode(mac)?, session),
+            response => panic!("Unexpected response {:?}", response.0),
+        };
+
+        hmac::verify(&confirm_key, &tagged(b"server", &transcript), &server_mac).unwrap();
+        let client = ChaChaPolySecurity::with_key(session_key)?;
+        assert_eq!(client.open(&session.seal(b"hello")?[DEVICE_ID_LEN..])?, b"hello");
+        Ok(client)
+    }
+
+    #[test]
+    fn pairs_with_correct_code() {
+        let server = PairingSecurity::new(Arc::new(DeviceStore::load(None).unwrap())).unwrap();
+        let code = server.pairing_code().unwrap().borrow().to_lowercase();
+        pair(&server, &code).unwrap();
+        assert_eq!(server.devices().unwrap().list().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn rejects_wrong_code_and_rotates() {
+        let server = PairingSecurity::new(Arc::new(DeviceStore::load(None).unwrap())).unwrap();
+        let code = server.pairing_code().unwrap().borrow().clone();
+        assert!(pair(&server, "AAAA-AAAA").is_err());
+        assert_ne!(*server.pairing_code().unwrap().borrow(), code);
+        assert!(pair(&server, &code).is_err());
+    }
+
+    #[test]
+    fn keeps_other_pairings_after_wrong_code() {
+        let server = PairingSecurity::new(Arc::new(DeviceStore::load(None).unwrap())).unwrap();
+        let code = server.pairing_code().unwrap().borrow().clone();
+        let legitimate = start(&server, &code).unwrap();
+        assert!(pair(&server, "AAAA-AAAA").is_err());
+        confirm(&server, legitimate).unwrap();
+    }
+}
@@ -0,0 +1,89 @@
+use std::{num::NonZeroU32, path::Path};
+
+use anyhow::{anyhow, Result};
+use ring::{aead::CHACHA20_POLY1305, pbkdf2, rand::{SecureRandom, SystemRandom}};
+use tracing::info;
+
+use super::{read_key, write_key, ChaChaPolySecurity, Security};
+
+/// The length of the salt in bytes.
+const SALT_LEN: usize = 16;
+/// The number of PBKDF2 iterations the passphrase is stretched with.
+const ITERATIONS: u32 = 100_000;
+
+/// A security implementation that derives the ChaCha20-Poly1305 key from
+/// a passphrase typed on both ends, for servers without a screen to show
+/// the QR code on.
+///
+/// The key is derived using PBKDF2-HMAC-SHA256 with 100,000 iterations and
+/// a per-server salt, which clients read from the `robo-salt` header (base64)
+/// of the websocket handshake response. Clients that cannot read the headers
+/// (e.g. browsers) ask for it with a plaintext `SaltRequest` instead.
+pub struct PassphraseSecurity {
+    inner: ChaChaPolySecurity,
+    salt: Vec<u8>,
+}
+
+impl PassphraseSecurity {
+    /// Derives the key from the given passphrase and salt.
+    pub fn new(passphrase: &str, salt: Vec<u8>) -> Result<Self> {
+        let mut key = vec![0u8; CHACHA20_POLY1305.key_len()];
+        let iterations = NonZeroU32::new(ITERATIONS).unwrap();
+        pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, &salt, passphrase.as_bytes(), &mut key);
+        Ok(Self { inner: ChaChaPolySecurity::with_key(key)?, salt })
+    }
+
+    /// Derives the key with a freshly generated salt.
+    pub fn with_random_salt(passphrase: &str) -> Result<Self> {
+        let mut salt = vec![0u8; SALT_LEN];
+        SystemRandom::new().fill(&mut salt).map_err(|_| anyhow!("Could not generate salt"))?;
+        Self::new(passphrase, salt)
+    }
+
+    /// Derives the key with the salt persisted at the given path, generating
+    /// (and persisting) a new one if there is none. Keeping the salt stable
+    /// lets clients cache the derived key across restarts.
+    pub fn persistent(passphrase: &str, salt_path: &Path) -> Result<Self> {
+        if let Some(salt) = read_key(salt_path)? {
+            return Self::new(passphrase, salt);
+        }
+        let security = Self::with_random_salt(passphrase)?;
+        write_key(salt_path, &security.salt)?;
+        info!("Generated new salt at {}", salt_path.display());
+        Ok(security)
+    }
+}
+
+impl Security for PassphraseSecurity {
+    fn kind(&self) -> &'static str { "passphrase" }
+
+    // The derived key must not be shared, clients derive it themselves
+    fn key(&self) -> Option<&[u8]> { None }
+
+    fn salt(&self) -> Option<&[u8]> { Some(&self.salt) }
+
+    fn seal(&self, value: &[u8]) -> Result<Vec<u8>> {
+        self.inner.seal(value)
+    }
+
+    fn open(&self, value: &[u8]) -> Result<Vec<u8>> {
+        self.inner.open(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::security::Security;
+
+    use super::PassphraseSecurity;
+
+    #[test]
+    fn derives_same_key_from_same_passphrase() {
+        let server = PassphraseSecurity::with_random_salt("correct horse").unwrap();
+        let client = PassphraseSecurity::new("correct horse", server.salt.clone()).unwrap();
+        assert_eq!(server.open(&client.seal(b"hello").unwrap()).unwrap(), b"hello");
+
+        let other = PassphraseSecurity::new("battery staple", server.salt.clone()).unwrap();
+        assert!(server.open(&other.seal(b"hello").unwrap()).is_err());
+    }
+}
@@ -0,0 +1,49 @@
+use std::{collections::{HashSet, VecDeque}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use ring::aead::NONCE_LEN;
+
+use super::OpenError;
+
+/// How far the timestamp of a message may deviate from the local clock.
+pub const MAX_MESSAGE_AGE: Duration = Duration::from_secs(30);
+
+/// The current time in milliseconds since the Unix epoch.
+pub fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Tracks the nonces of recently accepted messages to reject replays.
+/// Messages older than `MAX_MESSAGE_AGE` are rejected by their timestamp
+/// instead, so nonces only have to be remembered for that long.
+#[derive(Debug, Default)]
+pub struct ReplayWindow {
+    /// The nonces of accepted messages.
+    seen: HashSet<[u8; NONCE_LEN]>,
+    /// The accepted nonces with their timestamps, in the order they were accepted in.
+    /// Since messages arrive roughly in order of their timestamps, expired nonces
+    /// can be found at the front.
+    expiry: VecDeque<(u64, [u8; NONCE_LEN])>,
+}
+
+impl ReplayWindow {
+    /// Accepts a message with the given nonce and timestamp (in milliseconds
+    /// since the Unix epoch), if it is recent and has not been accepted before.
+    pub fn accept(&mut self, nonce: [u8; NONCE_LEN], timestamp: u64, now: u64) -> Result<(), OpenError> {
+        let max_age = MAX_MESSAGE_AGE.as_millis() as u64;
+        if timestamp.saturating_add(max_age) < now || timestamp > now.saturating_add(max_age) {
+            return Err(OpenError::Stale);
+        }
+        while let Some(&(seen_timestamp, seen_nonce)) = self.expiry.front() {
+            if seen_timestamp.saturating_add(max_age) >= now {
+                break;
+            }
+            self.expiry.pop_front();
+            self.seen.remove(&seen_nonce);
+        }
+        if !self.seen.insert(nonce) {
+            return Err(OpenError::Replayed);
+        }
+        self.expiry.push_back((timestamp, nonce));
+        Ok(())
+    }
+}
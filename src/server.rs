@@ -1,24 +1,62 @@
-use std::{str, net::SocketAddr, sync::Arc};
+use std::{fmt, str, net::SocketAddr, sync::{Arc, atomic::{AtomicU64, Ordering}}, time::Duration};
 
-use anyhow::Result;
-use async_tungstenite::{tokio::accept_async, tungstenite::Message};
+use anyhow::{anyhow, Result};
+use async_tungstenite::{tokio::accept_hdr_async, tungstenite::{Message, handshake::server::{Request as HandshakeRequest, Response as HandshakeResponse}, http::HeaderValue}, WebSocketStream};
 use druid::Data;
-use futures::StreamExt;
-use tokio::{net::{TcpListener, TcpStream}, sync::mpsc};
+use futures::{AsyncRead, AsyncWrite, SinkExt, StreamExt, future::BoxFuture, FutureExt};
+use tokio::{net::TcpListener, sync::mpsc::{self, error::TrySendError}, task::spawn_blocking, time::{interval, sleep, timeout}};
 use tracing::{info, error, warn};
 
-use crate::{security::Security, protocol::Action};
+use crate::{approval::{Allowlist, Approval, ApprovalRequest}, connections::{ConnectionCommand, Connections}, control::{Acquire, ControlLock}, pause::Pause, tls::TlsConfig, security::{OpenError, Security, SharedSecurity}, protocol::{Action, Request, Response, ErrorCode, ControlCommand, ControlRequest, ClientHello, ServerHello, ServerStatus, PairingRequest, PairingResponse, SaltRequest, SaltResponse, PROTOCOL_VERSION}};
+
+/// A unique, server-assigned identifier for a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Data)]
+pub struct ClientId(u64);
+
+impl ClientId {
+    pub fn next() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl fmt::Display for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
 
 #[derive(Debug, Clone, Data)]
 pub struct ClientInfo {
-    pub name: String,
+    pub id: ClientId,
+    /// The human-readable name supplied by the client, if any.
+    pub name: Option<String>,
+    pub addr: String,
+}
+
+impl fmt::Display for ClientInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{} {} ({})", self.id, name, self.addr),
+            None => write!(f, "{} {}", self.id, self.addr),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum MainThreadMessage {
-    Perform(Action),
+    Perform(ClientId, Action),
+    /// Asks whether a new client may connect.
+    RequestApproval(ApprovalRequest),
+    /// Withdraws the approval request of a client, because it timed out or the client disconnected.
+    CancelApproval(ClientId),
     DidConnect(ClientInfo),
+    DidUpdate(ClientInfo),
     DidDisconnect(ClientInfo),
+    /// Releases the input held by a client, e.g. because it lost control.
+    Release(ClientId),
+    DidPause,
+    DidResume,
     DidExit,
 }
 
@@ -26,29 +64,486 @@ pub enum MainThreadMessage {
 pub struct ServerContext {
     pub host: String,
     pub port: u16,
-    pub security: Arc<dyn Security + Send + Sync>,
+    pub security: SharedSecurity,
+    /// The certificate to serve over TLS with, if any.
+    pub tls: Option<TlsConfig>,
+    /// Set if new clients have to be approved, holding the hosts that are accepted without asking.
+    pub allowlist: Option<Arc<Allowlist>>,
+    /// The number of invalid messages after which a client is disconnected (0 for no limit).
+    pub max_client_errors: u32,
+    /// The command channels of the connected clients.
+    pub connections: Connections,
+    /// Whether actions are currently ignored.
+    pub pause: Pause,
+    /// Decides which client may act if control is exclusive.
+    pub control: Arc<ControlLock>,
     pub main_thread_tx: mpsc::Sender<MainThreadMessage>,
 }
 
-fn decode_action(raw: &[u8], security: &dyn Security) -> Result<Action> {
-    let raw = security.open(&raw)?;
-    let raw_str = str::from_utf8(raw.as_ref())?;
-    let action = serde_json::from_str(raw_str)?;
-    Ok(action)
+/// How long to wait for a new client to be approved before denying it.
+const APPROVAL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The number of actions per client that may wait to be performed, e.g. behind a sequence.
+const ACTION_QUEUE_LEN: usize = 64;
+
+/// The longest delay of a single sequence step.
+const MAX_STEP_DELAY: Duration = Duration::from_secs(10);
+
+/// The longest total delay of a sequence, including nested ones.
+const MAX_SEQUENCE_DELAY: Duration = Duration::from_secs(60);
+
+/// The most actions a request may be composed of, counting every
+/// sequence step and batch item (including nested ones).
+const MAX_COMPOSED_ACTIONS: usize = 1000;
+
+/// The most clicks a single mouse click may consist of (e.g. 3 for a triple-click).
+const MAX_CLICK_COUNT: u32 = 5;
+
+/// How often the paired devices are re-read to notice devices revoked from the command line.
+const DEVICE_REFRESH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The handshake response header advertising the salt for passphrase-derived keys.
+const SALT_HEADER: &str = "robo-salt";
+
+/// A decoded message from the client.
+enum ClientMessage {
+    Hello(ClientHello),
+    Request(Request),
+    Control(ControlRequest),
+}
+
+/// The reasons a message from a client can be rejected for.
+#[derive(Debug)]
+enum MessageError {
+    /// The message could not be opened.
+    Open(OpenError),
+    /// The message could not be opened for another reason.
+    Security(anyhow::Error),
+    InvalidUtf8,
+    InvalidJson(serde_json::Error),
+    UnknownAction(String),
+    InvalidRequest(serde_json::Error),
+    /// The request is valid, but exceeds the limits on what a single request may do.
+    ExceedsLimits(String),
 }
 
-async fn run_client_loop(name: &str, stream: TcpStream, ctx: ServerContext) -> Result<()> {
-    let mut ws_stream = accept_async(stream).await?;
-    while let Some(msg) = ws_stream.next().await {
-        match msg? {
-            Message::Binary(raw) => {
-                let action = decode_action(&raw, &*ctx.security);
-                match action {
-                    Ok(action) => {
-                        info!("Client {} sent {:?}", name, action);
-                        ctx.main_thread_tx.send(MainThreadMessage::Perform(action)).await?;
+impl MessageError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            Self::Open(OpenError::TooShort) => ErrorCode::TooShort,
+            Self::Open(OpenError::AuthFailure) => ErrorCode::AuthFailure,
+            Self::Open(OpenError::Stale) => ErrorCode::Stale,
+            Self::Open(OpenError::Replayed) => ErrorCode::Replayed,
+            Self::Security(_) => ErrorCode::Security,
+            Self::InvalidUtf8 => ErrorCode::InvalidUtf8,
+            Self::InvalidJson(_) => ErrorCode::InvalidJson,
+            Self::UnknownAction(_) => ErrorCode::UnknownAction,
+            Self::InvalidRequest(_) | Self::ExceedsLimits(_) => ErrorCode::InvalidRequest,
+        }
+    }
+
+    fn into_response(self, seq: Option<u64>) -> Response {
+        Response::Error { seq, code: self.code(), message: self.to_string() }
+    }
+}
+
+impl From<anyhow::Error> for MessageError {
+    fn from(error: anyhow::Error) -> Self {
+        match error.downcast::<OpenError>() {
+            Ok(error) => Self::Open(error),
+            Err(error) => Self::Security(error),
+        }
+    }
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Open(e) => write!(f, "{}", e),
+            Self::Security(e) => write!(f, "{}", e),
+            Self::InvalidUtf8 => write!(f, "Message is not valid UTF-8"),
+            Self::InvalidJson(e) => write!(f, "Message is not valid JSON: {}", e),
+            Self::UnknownAction(name) => write!(f, "Unknown action {}", name),
+            Self::InvalidRequest(e) => write!(f, "Invalid request: {}", e),
+            Self::ExceedsLimits(reason) => write!(f, "Invalid request: {}", reason),
+        }
+    }
+}
+
+/// Finds the name of the action in a request object, if it is not a known one.
+fn unknown_action(value: &serde_json::Value) -> Option<String> {
+    value.as_object()?
+        .keys()
+        .find(|&key| key != "seq" && !Action::NAMES.contains(&key.as_str()) && !ControlCommand::NAMES.contains(&key.as_str()))
+        .cloned()
+}
+
+/// Decodes a (decrypted) message, returning its sequence id alongside
+/// so that errors can be attributed to a request if possible.
+fn decode_message(raw: &[u8]) -> (Option<u64>, Result<ClientMessage, MessageError>) {
+    let raw_str = match str::from_utf8(raw) {
+        Ok(raw_str) => raw_str,
+        Err(_) => return (None, Err(MessageError::InvalidUtf8)),
+    };
+    let mut value: serde_json::Value = match serde_json::from_str(raw_str) {
+        Ok(value) => value,
+        Err(e) => return (None, Err(MessageError::InvalidJson(e))),
+    };
+    let seq = value.get("seq").and_then(|s| s.as_u64());
+    let is_control = ControlCommand::NAMES.iter().any(|&name| value.get(name).is_some());
+    let message = match value.get_mut("hello") {
+        Some(hello) => serde_json::from_value(hello.take()).map(ClientMessage::Hello).map_err(MessageError::InvalidRequest),
+        None if is_control => {
+            serde_json::from_value(value).map(ClientMessage::Control).map_err(MessageError::InvalidRequest)
+        },
+        None => match unknown_action(&value) {
+            Some(name) => Err(MessageError::UnknownAction(name)),
+            None => serde_json::from_value(value).map(ClientMessage::Request).map_err(MessageError::InvalidRequest),
+        },
+    };
+    (seq, message)
+}
+
+/// Checks that the given action stays within the limits, so a single
+/// request cannot occupy the client or the main thread for too long.
+fn check_limits(action: &Action) -> Result<(), MessageError> {
+    fn count(actions: &mut usize) -> Result<(), String> {
+        *actions += 1;
+        if *actions > MAX_COMPOSED_ACTIONS {
+            return Err(format!("Requests may be composed of at most {} actions", MAX_COMPOSED_ACTIONS));
+        }
+        Ok(())
+    }
+
+    fn visit(action: &Action, actions: &mut usize, delay_ms: &mut u64) -> Result<(), String> {
+        match action {
+            Action::Sequence { steps } => for step in steps {
+                count(actions)?;
+                if step.delay_ms > MAX_STEP_DELAY.as_millis() as u64 {
+                    return Err(format!("Steps may be delayed by at most {} ms", MAX_STEP_DELAY.as_millis()));
+                }
+                *delay_ms += step.delay_ms;
+                if *delay_ms > MAX_SEQUENCE_DELAY.as_millis() as u64 {
+                    return Err(format!("Sequences may take at most {} ms", MAX_SEQUENCE_DELAY.as_millis()));
+                }
+                visit(&step.action, actions, delay_ms)?;
+            },
+            Action::Batch(batch) => for action in batch {
+                count(actions)?;
+                visit(action, actions, delay_ms)?;
+            },
+            Action::MouseClick { count, .. } if *count > MAX_CLICK_COUNT => {
+                return Err(format!("Mouse clicks may consist of at most {} clicks", MAX_CLICK_COUNT));
+            },
+            _ => {},
+        }
+        Ok(())
+    }
+    visit(action, &mut 0, &mut 0).map_err(MessageError::ExceedsLimits)
+}
+
+fn server_hello(client: ClientId, ctx: &ServerContext) -> ServerHello {
+    ServerHello {
+        version: PROTOCOL_VERSION,
+        actions: Action::NAMES.iter().map(|&n| n.to_owned()).collect(),
+        security: vec![ctx.security.kind().to_owned()],
+        status: server_status(client, ctx),
+    }
+}
+
+fn server_status(client: ClientId, ctx: &ServerContext) -> ServerStatus {
+    ServerStatus {
+        paused: ctx.pause.is_paused(),
+        control: ctx.control.status(client),
+    }
+}
+
+fn encode_response(response: &Response, security: &dyn Security) -> Result<Vec<u8>> {
+    let raw = serde_json::to_vec(response)?;
+    let sealed = security.seal(&raw)?;
+    Ok(sealed)
+}
+
+/// Extracts the client name from the `name` query parameter of the
+/// websocket URL, if present.
+fn name_from_query(request: &HandshakeRequest) -> Option<String> {
+    let query = request.uri().query()?;
+    form_urlencoded::parse(query.as_bytes())
+        .find(|(key, _)| key == "name")
+        .map(|(_, value)| value.into_owned())
+        .filter(|name| !name.is_empty())
+}
+
+/// Forwards an action to the main thread. Delays of sequences are awaited
+/// here, so neither the GUI nor the headless event loop has to block.
+fn dispatch_action(client: ClientId, action: Action, ctx: &ServerContext) -> BoxFuture<'_, Result<()>> {
+    async move {
+        match action {
+            Action::Sequence { steps } => for step in steps {
+                sleep(Duration::from_millis(step.delay_ms)).await;
+                dispatch_action(client, step.action, ctx).await?;
+            },
+            Action::Batch(actions) if actions.iter().any(Action::is_timed) => for action in actions {
+                dispatch_action(client, action, ctx).await?;
+            },
+            action => {
+                // Steps of sequences are checked individually, since the server
+                // may be paused or control may change hands in the meantime
+                if ctx.pause.is_paused() {
+                    return Err(anyhow!("Server is paused"));
+                }
+                match ctx.control.acquire(client, false) {
+                    Acquire::Granted => {},
+                    Acquire::TakenFrom(previous) => {
+                        info!("Client {} took over control from {}", client, previous);
+                        release_input(previous, ctx).await;
                     },
-                    Err(e) => warn!("Could not decode action: {}", e),
+                    Acquire::Denied => return Err(anyhow!("Another client has control")),
+                }
+                ctx.main_thread_tx.send(MainThreadMessage::Perform(client, action)).await?;
+            },
+        }
+        Ok(())
+    }.boxed()
+}
+
+/// Forwards the actions of a client to the main thread in order. This runs
+/// separately from the client loop, so the connection stays responsive
+/// (e.g. to being disconnected) while a sequence waits for its delays.
+async fn run_dispatcher(client: ClientId, mut actions: mpsc::Receiver<Action>, ctx: ServerContext) {
+    while let Some(action) = actions.recv().await {
+        if let Err(e) = dispatch_action(client, action, &ctx).await {
+            warn!("Could not perform action of client {}: {}", client, e);
+        }
+    }
+}
+
+/// Handles a plaintext pairing message, switching the connection over
+/// to the session security once pairing has completed. Each connection
+/// may only have one pending pairing, tracked by `pending`.
+async fn handle_pairing(info: &ClientInfo, raw: &str, session: &mut Option<SharedSecurity>, pending: &mut bool, ctx: &ServerContext) -> PairingResponse {
+    let result = match serde_json::from_str::<PairingRequest>(raw) {
+        Ok(PairingRequest::Start { .. }) if *pending => Err(anyhow!("Another pairing is pending on this connection")),
+        Ok(request) => {
+            if matches!(request, PairingRequest::Confirm { .. }) {
+                *pending = false;
+            }
+            // Deriving the keys is deliberately expensive, so we do it off the async workers
+            let server_security = ctx.security.clone();
+            spawn_blocking(move || server_security.pair(request)).await.unwrap_or_else(|e| Err(e.into()))
+        },
+        Err(e) => Err(e.into()),
+    };
+    match result {
+        Ok((response, paired)) => {
+            if matches!(response, PairingResponse::Challenge { .. }) {
+                *pending = true;
+            }
+            if let Some(paired) = paired {
+                info!("Client {} paired", info);
+                start_session(info, session, paired, ctx);
+            }
+            response
+        },
+        Err(e) => {
+            warn!("Could not pair with {}: {}", info, e);
+            PairingResponse::Failed { message: e.to_string() }
+        },
+    }
+}
+
+/// Switches the connection over to the given (client-specific) security.
+fn start_session(info: &ClientInfo, session: &mut Option<SharedSecurity>, security: SharedSecurity, ctx: &ServerContext) {
+    if let Some(device) = security.device_id() {
+        // Lets revoking the device disconnect the client
+        ctx.connections.set_device(info.id, device);
+    }
+    *session = Some(security);
+}
+
+fn no_control(seq: Option<u64>) -> Response {
+    Response::Error { seq, code: ErrorCode::NoControl, message: "Another client has control".to_owned() }
+}
+
+/// Asks the main thread to release the input held by the given client.
+async fn release_input(client: ClientId, ctx: &ServerContext) {
+    if let Err(e) = ctx.main_thread_tx.send(MainThreadMessage::Release(client)).await {
+        error!("Could not forward release to main thread: {}", e);
+    }
+}
+
+/// Handles a single binary message, returning the response to send, if any.
+async fn handle_message(info: &mut ClientInfo, raw: &[u8], is_first: bool, security: &SharedSecurity, actions: &mpsc::Sender<Action>, ctx: &ServerContext) -> Option<Response> {
+    let raw = match security.open(raw) {
+        Ok(raw) => raw,
+        Err(e) => {
+            warn!("Could not open message from {}: {}", info, e);
+            return Some(MessageError::from(e).into_response(None));
+        },
+    };
+    let (seq, message) = decode_message(&raw);
+    match message {
+        Ok(ClientMessage::Hello(hello)) if is_first => {
+            info!("Client {} speaks protocol version {}", info, hello.version);
+            if let Some(name) = hello.name.filter(|name| !name.is_empty()) {
+                info!("Client {} is now known as {}", info, name);
+                info.name = Some(name);
+                if let Err(e) = ctx.main_thread_tx.send(MainThreadMessage::DidUpdate(info.clone())).await {
+                    error!("Could not forward client update to main thread: {}", e);
+                }
+            }
+            Some(Response::Hello(server_hello(info.id, ctx)))
+        },
+        Ok(ClientMessage::Hello(_)) => {
+            warn!("Client {} sent a hello after other messages", info);
+            Some(Response::Error { seq, code: ErrorCode::UnexpectedHello, message: "Hello must be the first message".to_owned() })
+        },
+        Ok(ClientMessage::Request(Request { seq, action })) => {
+            info!("Client {} sent {:?}", info, action);
+            if let Err(e) = check_limits(&action) {
+                warn!("Rejecting request from {}: {}", info, e);
+                return Some(e.into_response(seq));
+            }
+            if ctx.pause.is_paused() {
+                return Some(Response::Error { seq, code: ErrorCode::Paused, message: "Server is paused".to_owned() });
+            }
+            match ctx.control.acquire(info.id, false) {
+                Acquire::Granted => {},
+                Acquire::TakenFrom(previous) => {
+                    info!("Client {} took over control from {}", info, previous);
+                    release_input(previous, ctx).await;
+                },
+                Acquire::Denied => return Some(no_control(seq)),
+            }
+            match actions.try_send(action) {
+                Ok(()) => {},
+                Err(TrySendError::Full(_)) => {
+                    warn!("Too many actions of {} are waiting", info);
+                    return Some(Response::Error { seq, code: ErrorCode::Busy, message: "Too many actions are waiting to be performed".to_owned() });
+                },
+                Err(TrySendError::Closed(_)) => {
+                    error!("Could not forward action of {}", info);
+                    return Some(Response::Error { seq, code: ErrorCode::Internal, message: "Actions can no longer be performed".to_owned() });
+                },
+            }
+            seq.map(|seq| Response::Ack { seq })
+        },
+        Ok(ClientMessage::Control(ControlRequest { seq, command: ControlCommand::RequestControl {} })) => match ctx.control.acquire(info.id, true) {
+            Acquire::Denied => Some(no_control(seq)),
+            acquired => {
+                info!("Client {} holds control", info);
+                if let Acquire::TakenFrom(previous) = acquired {
+                    release_input(previous, ctx).await;
+                }
+                seq.map(|seq| Response::Ack { seq })
+            },
+        },
+        Ok(ClientMessage::Control(ControlRequest { seq, command: ControlCommand::ReleaseControl {} })) => {
+            ctx.control.release(info.id);
+            // Releases of held input would be rejected from now on
+            release_input(info.id, ctx).await;
+            seq.map(|seq| Response::Ack { seq })
+        },
+        Err(e) => {
+            warn!("Could not decode request from {}: {}", info, e);
+            Some(e.into_response(seq))
+        },
+    }
+}
+
+async fn send_status<S>(info: &ClientInfo, ws_stream: &mut WebSocketStream<S>, security: &SharedSecurity, ctx: &ServerContext) -> Result<()>
+    where S: AsyncRead + AsyncWrite + Unpin {
+    match encode_response(&Response::Status(server_status(info.id, ctx)), &**security) {
+        Ok(raw) => ws_stream.send(Message::Binary(raw)).await?,
+        Err(e) => warn!("Could not send status to {}: {}", info, e),
+    }
+    Ok(())
+}
+
+async fn run_client_loop<S>(info: &mut ClientInfo, ws_stream: &mut WebSocketStream<S>, mut early: Option<Message>, commands: &mut mpsc::UnboundedReceiver<ConnectionCommand>, actions: &mpsc::Sender<Action>, ctx: ServerContext) -> Result<()>
+    where S: AsyncRead + AsyncWrite + Unpin {
+    let mut is_first = true;
+    // The security specific to this client, if any
+    let mut session = None;
+    let mut errors = 0;
+    let too_many_errors = |errors| ctx.max_client_errors > 0 && errors >= ctx.max_client_errors;
+    // Whether this client has started pairing, but not confirmed it yet
+    let mut pairing_pending = false;
+    let mut paused = ctx.pause.subscribe();
+    let mut holder = ctx.control.subscribe();
+    loop {
+        // A message received while waiting for approval is handled first
+        let msg = match early.take() {
+            Some(msg) => msg,
+            None => tokio::select! {
+                msg = ws_stream.next() => match msg {
+                    Some(msg) => msg?,
+                    None => break,
+                },
+                command = commands.recv() => match command {
+                    Some(ConnectionCommand::Disconnect) | None => {
+                        info!("Disconnecting {}", info);
+                        ws_stream.close(None).await?;
+                        break;
+                    },
+                },
+                Ok(()) = paused.changed() => {
+                    send_status(info, ws_stream, session.as_ref().unwrap_or(&ctx.security), &ctx).await?;
+                    continue;
+                },
+                Ok(()) = holder.changed() => {
+                    send_status(info, ws_stream, session.as_ref().unwrap_or(&ctx.security), &ctx).await?;
+                    continue;
+                },
+            },
+        };
+        match msg {
+            Message::Binary(raw) => {
+                if session.is_none() {
+                    if let Some(security) = ctx.security.session(&raw) {
+                        start_session(info, &mut session, security, &ctx);
+                    }
+                }
+                let security = session.as_ref().unwrap_or(&ctx.security);
+                let mut responses = Vec::new();
+                if let Some(response) = handle_message(info, &raw, is_first, security, actions, &ctx).await {
+                    if matches!(response, Response::Error { code, .. } if !matches!(code, ErrorCode::Internal | ErrorCode::Paused | ErrorCode::NoControl | ErrorCode::Busy)) {
+                        errors += 1;
+                    }
+                    responses.push(response);
+                }
+                let too_many_errors = too_many_errors(errors);
+                if too_many_errors {
+                    warn!("Disconnecting {} after {} invalid messages", info, errors);
+                    responses.push(Response::Error { seq: None, code: ErrorCode::TooManyErrors, message: format!("Too many invalid messages ({})", errors) });
+                }
+                for response in responses {
+                    // Clients without a session (e.g. unpaired ones) cannot be replied to
+                    match encode_response(&response, &**security) {
+                        Ok(raw) => ws_stream.send(Message::Binary(raw)).await?,
+                        Err(e) => warn!("Could not send response to {}: {}", info, e),
+                    }
+                }
+                if too_many_errors {
+                    ws_stream.close(None).await?;
+                    break;
+                }
+                is_first = false;
+            },
+            Message::Text(raw) if matches!(serde_json::from_str(&raw), Ok(SaltRequest::GetSalt {})) => {
+                let response = SaltResponse { salt: ctx.security.salt().map(base64::encode) };
+                ws_stream.send(Message::Text(serde_json::to_string(&response)?)).await?;
+            },
+            Message::Text(raw) => {
+                let response = handle_pairing(info, &raw, &mut session, &mut pairing_pending, &ctx).await;
+                if matches!(response, PairingResponse::Failed { .. }) {
+                    errors += 1;
+                }
+                ws_stream.send(Message::Text(serde_json::to_string(&response)?)).await?;
+                if too_many_errors(errors) {
+                    warn!("Disconnecting {} after {} invalid messages", info, errors);
+                    ws_stream.close(None).await?;
+                    break;
                 }
             },
             Message::Close(_) => break,
@@ -58,21 +553,117 @@ async fn run_client_loop(name: &str, stream: TcpStream, ctx: ServerContext) -> R
     Ok(())
 }
 
-pub async fn handle_client(stream: TcpStream, addr: SocketAddr, ctx: ServerContext) -> Result<()> {
-    let info = ClientInfo { name: addr.to_string() };
+/// Whether a client may connect after waiting for approval.
+enum Admission {
+    Accepted {
+        /// The first message the client sent while waiting, if any.
+        early: Option<Message>,
+    },
+    Rejected,
+}
+
+/// Asks the main thread whether the given client may connect,
+/// unless its host is on the allowlist already.
+async fn approve<S>(info: &ClientInfo, addr: SocketAddr, allowlist: &Allowlist, ws_stream: &mut WebSocketStream<S>, ctx: &ServerContext) -> Result<Admission>
+    where S: AsyncRead + AsyncWrite + Unpin {
+    let host = addr.ip().to_string();
+    if allowlist.contains(&host) {
+        return Ok(Admission::Accepted { early: None });
+    }
 
+    info!("Waiting for {} to be approved", info);
+    let (request, reply) = ApprovalRequest::new(info.clone());
+    ctx.main_thread_tx.send(MainThreadMessage::RequestApproval(request)).await?;
+
+    // We keep reading from the client to notice if it disconnects
+    let mut early = None;
+    let reply = timeout(APPROVAL_TIMEOUT, reply);
+    tokio::pin!(reply);
+    let approval = loop {
+        tokio::select! {
+            result = &mut reply => break result.ok().and_then(Result::ok),
+            // Later messages wait in the stream until the client loop reads them
+            msg = ws_stream.next(), if early.is_none() => match msg {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                    info!("Client {} disconnected while waiting for approval", info);
+                    break None;
+                },
+                Some(Ok(msg)) => early = Some(msg),
+            },
+        }
+    };
+
+    match approval {
+        Some(Approval::Deny) => Ok(Admission::Rejected),
+        Some(approval) => {
+            if approval == Approval::AcceptAlways {
+                allowlist.add(&host)?;
+            }
+            Ok(Admission::Accepted { early })
+        },
+        None => {
+            ctx.main_thread_tx.send(MainThreadMessage::CancelApproval(info.id)).await?;
+            Ok(Admission::Rejected)
+        },
+    }
+}
+
+// The handshake callback's error type is dictated by tungstenite
+#[allow(clippy::result_large_err)]
+pub async fn handle_client<S>(stream: S, addr: SocketAddr, ctx: ServerContext) -> Result<()>
+    where S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin {
+    let mut query_name = None;
+    let salt = ctx.security.salt().and_then(|salt| HeaderValue::from_str(&base64::encode(salt)).ok());
+    let mut ws_stream = accept_hdr_async(stream, |request: &HandshakeRequest, mut response: HandshakeResponse| {
+        query_name = name_from_query(request);
+        if let Some(salt) = salt {
+            response.headers_mut().insert(SALT_HEADER, salt);
+        }
+        Ok(response)
+    }).await?;
+
+    let mut info = ClientInfo {
+        id: ClientId::next(),
+        name: query_name,
+        addr: addr.to_string(),
+    };
+
+    let early = match &ctx.allowlist {
+        Some(allowlist) => match approve(&info, addr, allowlist, &mut ws_stream, &ctx).await? {
+            Admission::Accepted { early } => early,
+            Admission::Rejected => {
+                info!("Client {} was denied", info);
+                // The client may have disconnected already
+                let _ = ws_stream.close(None).await;
+                return Ok(());
+            },
+        },
+        None => None,
+    };
+
+    let mut commands = ctx.connections.register(info.id);
     ctx.main_thread_tx.send(MainThreadMessage::DidConnect(info.clone())).await?;
-    info!("Client {} connected!", info.name);
+    info!("Client {} connected!", info);
+
+    let (actions, pending_actions) = mpsc::channel(ACTION_QUEUE_LEN);
+    let dispatcher = tokio::spawn(run_dispatcher(info.id, pending_actions, ctx.clone()));
 
     {
         let ctx = ctx.clone();
-        if let Err(e) = run_client_loop(&info.name, stream, ctx).await {
+        if let Err(e) = run_client_loop(&mut info, &mut ws_stream, early, &mut commands, &actions, ctx).await {
             error!("Error while running client loop: {}", e);
         };
     }
 
+    // Stops pending sequences, so the input released below stays released
+    dispatcher.abort();
+    let _ = dispatcher.await;
+
+    ctx.connections.unregister(info.id);
+    ctx.control.release(info.id);
+
     ctx.main_thread_tx.send(MainThreadMessage::DidDisconnect(info.clone())).await?;
-    info!("Client {} disconnected", info.name);
+    info!("Client {} disconnected", info);
 
     Ok(())
 }
@@ -81,16 +672,464 @@ pub async fn run(ctx: ServerContext) {
     let host = ctx.host.clone();
     let port = ctx.port;
 
-    info!("Starting server on {}:{}", host, port);
+    info!("Starting server on {}:{}{}", host, port, if ctx.tls.is_some() { " (TLS)" } else { "" });
     info!("Security: {} (key: {})", ctx.security.kind(), ctx.security.key().map(base64::encode).unwrap_or_else(|| "none".to_owned()));
 
-    let listener = TcpListener::bind((host, port)).await.expect("Could not start TCP server");
-    while let Ok((stream, client_addr)) = listener.accept().await {
-        let ctx = ctx.clone();
+    if let Some(tls) = &ctx.tls {
+        info!("Certificate fingerprint (SHA-256): {}", tls.fingerprint);
+    }
+
+    if let Some(mut codes) = ctx.security.pairing_code() {
+        tokio::spawn(async move {
+            loop {
+                info!("Pairing code: {}", *codes.borrow());
+                if codes.changed().await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    if let Some(devices) = ctx.security.devices() {
+        let connections = ctx.connections.clone();
         tokio::spawn(async move {
-            handle_client(stream, client_addr, ctx).await.expect("Error while handling client");
+            let mut paired = devices.subscribe();
+            let mut refresh = interval(DEVICE_REFRESH_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = refresh.tick() => {
+                        // Revoking from the command line only changes the file, which is re-read on access
+                        if let Err(e) = devices.list() {
+                            warn!("Could not refresh paired devices: {}", e);
+                        }
+                    },
+                    Ok(()) = paired.changed() => {},
+                }
+                connections.disconnect_revoked(&paired.borrow_and_update());
+            }
+        });
+    }
+
+    {
+        let mut paused = ctx.pause.subscribe();
+        let main_thread_tx = ctx.main_thread_tx.clone();
+        tokio::spawn(async move {
+            while paused.changed().await.is_ok() {
+                let msg = if *paused.borrow() {
+                    info!("Paused, ignoring actions");
+                    MainThreadMessage::DidPause
+                } else {
+                    info!("Resumed");
+                    MainThreadMessage::DidResume
+                };
+                if main_thread_tx.send(msg).await.is_err() {
+                    break;
+                }
+            }
         });
     }
 
-    ctx.main_thread_tx.send(MainThreadMessage::DidExit).await.expect("Could not send exit message to main thread");
+    match TcpListener::bind((host, port)).await {
+        Ok(listener) => while let Ok((stream, client_addr)) = listener.accept().await {
+            let ctx = ctx.clone();
+            tokio::spawn(async move {
+                let result = match ctx.tls.clone() {
+                    Some(tls) => match tls.acceptor.accept(stream).await {
+                        Ok(stream) => handle_client(stream, client_addr, ctx).await,
+                        Err(e) => Err(anyhow!("TLS handshake failed: {}", e)),
+                    },
+                    None => handle_client(stream, client_addr, ctx).await,
+                };
+                if let Err(e) = result {
+                    warn!("Error while handling client {}: {}", client_addr, e);
+                }
+            });
+        },
+        Err(e) => error!("Could not start TCP server: {}", e),
+    }
+
+    if let Err(e) = ctx.main_thread_tx.send(MainThreadMessage::DidExit).await {
+        error!("Could not send exit message to main thread: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::{Arc, Mutex}, thread, time::Duration};
+
+    use async_tungstenite::{tokio::{connect_async, ConnectStream}, tungstenite::Message, WebSocketStream};
+    use futures::{SinkExt, StreamExt};
+    use serde_json::json;
+    use tokio::{net::TcpListener, sync::mpsc, time::{sleep, timeout}};
+
+    use crate::{approval::{Allowlist, Approval}, backend::RecordingBackend, connections::Connections, control::{Acquire, ControlLock, ControlPolicy}, controller::Controller, pause::Pause, protocol::{Action, ControlStatus, ErrorCode, Key, MouseButton, PairingResponse, Response, SaltResponse, ServerStatus, Vec2, PROTOCOL_VERSION}, security::{DeviceSecurity, DeviceStore, EmptySecurity, PairingSecurity, PassphraseSecurity, Security}};
+
+    use super::{run, ClientId, MainThreadMessage, ServerContext};
+
+    /// Starts a server performing actions with a recording backend,
+    /// returning a connected client and the recorded actions.
+    async fn start() -> (WebSocketStream<ConnectStream>, Arc<Mutex<Vec<Action>>>) {
+        start_with(|_| {}).await
+    }
+
+    /// Starts a server like `start`, with a context customized by the given function.
+    /// Clients that have to be approved are denied.
+    async fn start_with(configure: impl FnOnce(&mut ServerContext)) -> (WebSocketStream<ConnectStream>, Arc<Mutex<Vec<Action>>>) {
+        let port = TcpListener::bind(("127.0.0.1", 0)).await.unwrap().local_addr().unwrap().port();
+        let (tx, mut rx) = mpsc::channel(4);
+        let backend = RecordingBackend::new();
+        let log = backend.log();
+
+        let mut ctx = ServerContext {
+            host: "127.0.0.1".to_owned(),
+            port,
+            security: Arc::new(EmptySecurity),
+            tls: None,
+            allowlist: None,
+            max_client_errors: 3,
+            connections: Connections::default(),
+            pause: Pause::default(),
+            control: Arc::new(ControlLock::new(ControlPolicy::Shared, Duration::ZERO)),
+            main_thread_tx: tx,
+        };
+        configure(&mut ctx);
+
+        let pause = ctx.pause.clone();
+        thread::spawn(move || {
+            let mut controller = Controller::new(Box::new(backend), pause);
+            while let Some(msg) = rx.blocking_recv() {
+                match msg {
+                    MainThreadMessage::Perform(client, action) => controller.perform(client, action).unwrap(),
+                    MainThreadMessage::DidDisconnect(client) => controller.release(client.id).unwrap(),
+                    MainThreadMessage::Release(client) => controller.release(client).unwrap(),
+                    MainThreadMessage::RequestApproval(request) => request.answer(Approval::Deny),
+                    MainThreadMessage::DidPause => controller.release_all().unwrap(),
+                    _ => {},
+                }
+            }
+        });
+        tokio::spawn(run(ctx));
+
+        for _ in 0..50 {
+            if let Ok((ws_stream, _)) = connect_async(format!("ws://127.0.0.1:{}", port)).await {
+                return (ws_stream, log);
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+        panic!("Could not connect to server");
+    }
+
+    async fn send(ws_stream: &mut WebSocketStream<ConnectStream>, value: serde_json::Value) {
+        ws_stream.send(Message::Binary(serde_json::to_vec(&value).unwrap())).await.unwrap();
+    }
+
+    async fn receive(ws_stream: &mut WebSocketStream<ConnectStream>) -> Response {
+        let msg = timeout(Duration::from_secs(5), ws_stream.next()).await.unwrap().unwrap().unwrap();
+        serde_json::from_slice(&msg.into_data()).unwrap()
+    }
+
+    async fn receive_error(ws_stream: &mut WebSocketStream<ConnectStream>) -> (Option<u64>, ErrorCode) {
+        match receive(ws_stream).await {
+            Response::Error { seq, code, .. } => (seq, code),
+            response => panic!("Expected error, got {:?}", response),
+        }
+    }
+
+    async fn receive_pairing(ws_stream: &mut WebSocketStream<ConnectStream>) -> PairingResponse {
+        match timeout(Duration::from_secs(10), ws_stream.next()).await.unwrap().unwrap().unwrap() {
+            Message::Text(raw) => serde_json::from_str(&raw).unwrap(),
+            msg => panic!("Expected pairing response, got {:?}", msg),
+        }
+    }
+
+    /// Waits until the given number of actions has been recorded.
+    async fn recorded(log: &Mutex<Vec<Action>>, count: usize) -> Vec<Action> {
+        for _ in 0..250 {
+            let actions = log.lock().unwrap().clone();
+            if actions.len() >= count {
+                return actions;
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+        panic!("Expected {} recorded actions, got {:?}", count, log.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn performs_and_acknowledges_actions() {
+        let (mut ws_stream, log) = start().await;
+
+        send(&mut ws_stream, json!({ "seq": 1, "keySequence": { "text": "hello" } })).await;
+        assert_eq!(receive(&mut ws_stream).await, Response::Ack { seq: 1 });
+
+        send(&mut ws_stream, json!({ "keyChord": { "modifiers": ["control"], "key": "tab" } })).await;
+
+        assert_eq!(recorded(&log, 4).await, vec![
+            Action::KeySequence { text: "hello".to_owned() },
+            Action::KeyDown { key: Key::Control },
+            Action::KeyClick { key: Key::Tab },
+            Action::KeyUp { key: Key::Control },
+        ]);
+    }
+
+    #[tokio::test]
+    async fn performs_sequences_in_order() {
+        let (mut ws_stream, log) = start().await;
+
+        send(&mut ws_stream, json!({ "seq": 1, "sequence": { "steps": [
+            { "action": { "keyDown": { "key": "meta" } } },
+            { "delayMs": 50, "action": { "keyUp": { "key": "meta" } } },
+            { "delayMs": 50, "action": { "keySequence": { "text": "terminal" } } },
+        ] } })).await;
+        assert_eq!(receive(&mut ws_stream).await, Response::Ack { seq: 1 });
+
+        assert_eq!(recorded(&log, 3).await, vec![
+            Action::KeyDown { key: Key::Meta },
+            Action::KeyUp { key: Key::Meta },
+            Action::KeySequence { text: "terminal".to_owned() },
+        ]);
+    }
+
+    #[tokio::test]
+    async fn stays_responsive_during_sequences() {
+        let connections = Connections::default();
+        let (mut ws_stream, log) = start_with(|ctx| ctx.connections = connections.clone()).await;
+
+        send(&mut ws_stream, json!({ "seq": 1, "sequence": { "steps": [
+            { "action": { "keyDown": { "key": "shift" } } },
+            { "delayMs": 10000, "action": { "keyUp": { "key": "shift" } } },
+        ] } })).await;
+        assert_eq!(receive(&mut ws_stream).await, Response::Ack { seq: 1 });
+        send(&mut ws_stream, json!({ "seq": 2, "sequence": { "steps": [{ "delayMs": 3600000, "action": { "keyClick": { "key": "tab" } } }] } })).await;
+        assert_eq!(receive_error(&mut ws_stream).await, (Some(2), ErrorCode::InvalidRequest));
+        assert_eq!(recorded(&log, 1).await, vec![Action::KeyDown { key: Key::Shift }]);
+
+        connections.disconnect_all();
+        let msg = timeout(Duration::from_secs(5), ws_stream.next()).await.unwrap();
+        assert!(matches!(msg, None | Some(Ok(Message::Close(_))) | Some(Err(_))), "Expected close, got {:?}", msg);
+    }
+
+    #[tokio::test]
+    async fn releases_held_input_on_disconnect() {
+        let (mut ws_stream, log) = start().await;
+
+        send(&mut ws_stream, json!({ "keyDown": { "key": "shift" } })).await;
+        send(&mut ws_stream, json!({ "mouseDown": { "button": "left" } })).await;
+        send(&mut ws_stream, json!({ "seq": 1, "mouseMoveBy": { "delta": { "x": 10, "y": 0 } } })).await;
+        assert_eq!(receive(&mut ws_stream).await, Response::Ack { seq: 1 });
+        ws_stream.close(None).await.unwrap();
+
+        assert_eq!(recorded(&log, 5).await, vec![
+            Action::KeyDown { key: Key::Shift },
+            Action::MouseDown { button: MouseButton::Left },
+            Action::MouseMoveBy { delta: Vec2 { x: 10, y: 0 } },
+            Action::KeyUp { key: Key::Shift },
+            Action::MouseUp { button: MouseButton::Left },
+        ]);
+    }
+
+    #[tokio::test]
+    async fn sends_salt_on_request() {
+        let (mut ws_stream, _) = start_with(|ctx| ctx.security = Arc::new(PassphraseSecurity::new("secret", vec![1; 16]).unwrap())).await;
+
+        ws_stream.send(Message::Text(json!({ "getSalt": {} }).to_string())).await.unwrap();
+        match timeout(Duration::from_secs(5), ws_stream.next()).await.unwrap().unwrap().unwrap() {
+            Message::Text(raw) => assert_eq!(serde_json::from_str::<SaltResponse>(&raw).unwrap(), SaltResponse { salt: Some(base64::encode([1u8; 16])) }),
+            msg => panic!("Expected salt, got {:?}", msg),
+        }
+    }
+
+    #[tokio::test]
+    async fn replies_to_hello() {
+        let (mut ws_stream, _) = start().await;
+
+        send(&mut ws_stream, json!({ "hello": { "version": PROTOCOL_VERSION, "name": "Test" } })).await;
+
+        match receive(&mut ws_stream).await {
+            Response::Hello(hello) => {
+                assert_eq!(hello.version, PROTOCOL_VERSION);
+                assert!(hello.actions.iter().any(|a| a == "keySequence"));
+                assert_eq!(hello.security, vec!["none".to_owned()]);
+                assert_eq!(hello.status, ServerStatus { paused: false, control: None });
+            },
+            response => panic!("Expected hello, got {:?}", response),
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_invalid_requests() {
+        let (mut ws_stream, log) = start().await;
+
+        send(&mut ws_stream, json!({ "seq": 7, "teleport": {} })).await;
+        assert_eq!(receive_error(&mut ws_stream).await, (Some(7), ErrorCode::UnknownAction));
+
+        send(&mut ws_stream, json!({ "seq": 8, "keyDown": { "key": 42 } })).await;
+        assert_eq!(receive_error(&mut ws_stream).await, (Some(8), ErrorCode::InvalidRequest));
+
+        assert!(log.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn limits_click_counts() {
+        let (mut ws_stream, log) = start().await;
+
+        send(&mut ws_stream, json!({ "seq": 1, "mouseClick": { "count": 4294967295u32 } })).await;
+        assert_eq!(receive_error(&mut ws_stream).await, (Some(1), ErrorCode::InvalidRequest));
+        send(&mut ws_stream, json!({ "seq": 2, "batch": [{ "mouseClick": { "count": 6 } }] })).await;
+        assert_eq!(receive_error(&mut ws_stream).await, (Some(2), ErrorCode::InvalidRequest));
+
+        send(&mut ws_stream, json!({ "seq": 3, "mouseClick": { "count": 3 } })).await;
+        assert_eq!(receive(&mut ws_stream).await, Response::Ack { seq: 3 });
+        assert_eq!(recorded(&log, 3).await, vec![Action::MouseClick { button: MouseButton::Left, count: 1 }; 3]);
+    }
+
+    #[tokio::test]
+    async fn limits_composed_actions() {
+        let (mut ws_stream, log) = start().await;
+        let click = json!({ "keyClick": { "key": "tab" } });
+
+        send(&mut ws_stream, json!({ "seq": 1, "batch": vec![click.clone(); 1001] })).await;
+        assert_eq!(receive_error(&mut ws_stream).await, (Some(1), ErrorCode::InvalidRequest));
+        let nested = json!({ "batch": vec![click.clone(); 500] });
+        send(&mut ws_stream, json!({ "seq": 2, "batch": [nested.clone(), nested] })).await;
+        assert_eq!(receive_error(&mut ws_stream).await, (Some(2), ErrorCode::InvalidRequest));
+
+        send(&mut ws_stream, json!({ "seq": 3, "batch": vec![click; 1000] })).await;
+        assert_eq!(receive(&mut ws_stream).await, Response::Ack { seq: 3 });
+        assert_eq!(recorded(&log, 1000).await.len(), 1000);
+    }
+
+    #[tokio::test]
+    async fn only_accepts_approved_clients() {
+        let allowlist = Arc::new(Allowlist::load(None).unwrap());
+        let (mut ws_stream, _) = start_with(|ctx| ctx.allowlist = Some(allowlist.clone())).await;
+        let msg = timeout(Duration::from_secs(5), ws_stream.next()).await.unwrap();
+        assert!(matches!(msg, None | Some(Ok(Message::Close(_))) | Some(Err(_))), "Expected close, got {:?}", msg);
+
+        allowlist.add("127.0.0.1").unwrap();
+        let (mut ws_stream, _) = start_with(|ctx| ctx.allowlist = Some(allowlist)).await;
+        send(&mut ws_stream, json!({ "seq": 1, "keySequence": { "text": "hello" } })).await;
+        assert_eq!(receive(&mut ws_stream).await, Response::Ack { seq: 1 });
+    }
+
+    #[tokio::test]
+    async fn disconnects_after_too_many_errors() {
+        let (mut ws_stream, _) = start().await;
+
+        ws_stream.send(Message::Binary(vec![0xff])).await.unwrap();
+        assert_eq!(receive_error(&mut ws_stream).await, (None, ErrorCode::InvalidUtf8));
+        ws_stream.send(Message::Binary(b"{".to_vec())).await.unwrap();
+        assert_eq!(receive_error(&mut ws_stream).await, (None, ErrorCode::InvalidJson));
+        send(&mut ws_stream, json!({ "teleport": {} })).await;
+        assert_eq!(receive_error(&mut ws_stream).await, (None, ErrorCode::UnknownAction));
+        assert_eq!(receive_error(&mut ws_stream).await, (None, ErrorCode::TooManyErrors));
+
+        let msg = timeout(Duration::from_secs(5), ws_stream.next()).await.unwrap();
+        assert!(matches!(msg, None | Some(Ok(Message::Close(_))) | Some(Err(_))), "Expected close, got {:?}", msg);
+    }
+
+    #[tokio::test]
+    async fn disconnects_on_request() {
+        let connections = Connections::default();
+        let (mut ws_stream, _) = start_with(|ctx| ctx.connections = connections.clone()).await;
+        send(&mut ws_stream, json!({ "seq": 1, "keySequence": { "text": "hello" } })).await;
+        assert_eq!(receive(&mut ws_stream).await, Response::Ack { seq: 1 });
+
+        connections.disconnect_all();
+        let msg = timeout(Duration::from_secs(5), ws_stream.next()).await.unwrap();
+        assert!(matches!(msg, None | Some(Ok(Message::Close(_))) | Some(Err(_))), "Expected close, got {:?}", msg);
+    }
+
+    #[tokio::test]
+    async fn ignores_actions_while_paused() {
+        let pause = Pause::default();
+        let (mut ws_stream, log) = start_with(|ctx| ctx.pause = pause.clone()).await;
+
+        pause.set(true);
+        assert_eq!(receive(&mut ws_stream).await, Response::Status(ServerStatus { paused: true, control: None }));
+        send(&mut ws_stream, json!({ "seq": 1, "keySequence": { "text": "hello" } })).await;
+        assert_eq!(receive_error(&mut ws_stream).await, (Some(1), ErrorCode::Paused));
+
+        pause.set(false);
+        assert_eq!(receive(&mut ws_stream).await, Response::Status(ServerStatus { paused: false, control: None }));
+        send(&mut ws_stream, json!({ "seq": 2, "keySequence": { "text": "world" } })).await;
+        assert_eq!(receive(&mut ws_stream).await, Response::Ack { seq: 2 });
+
+        assert_eq!(recorded(&log, 1).await, vec![Action::KeySequence { text: "world".to_owned() }]);
+    }
+
+    #[tokio::test]
+    async fn requires_explicitly_requested_control() {
+        let (mut ws_stream, log) = start_with(|ctx| ctx.control = Arc::new(ControlLock::new(ControlPolicy::Explicit, Duration::ZERO))).await;
+
+        send(&mut ws_stream, json!({ "seq": 1, "keySequence": { "text": "hello" } })).await;
+        assert_eq!(receive_error(&mut ws_stream).await, (Some(1), ErrorCode::NoControl));
+
+        send(&mut ws_stream, json!({ "seq": 2, "requestControl": {} })).await;
+        assert_eq!(receive(&mut ws_stream).await, Response::Ack { seq: 2 });
+        assert_eq!(receive(&mut ws_stream).await, Response::Status(ServerStatus { paused: false, control: Some(ControlStatus::Yours) }));
+        send(&mut ws_stream, json!({ "seq": 3, "keySequence": { "text": "world" } })).await;
+        assert_eq!(receive(&mut ws_stream).await, Response::Ack { seq: 3 });
+
+        send(&mut ws_stream, json!({ "seq": 4, "releaseControl": {} })).await;
+        assert_eq!(receive(&mut ws_stream).await, Response::Ack { seq: 4 });
+        assert_eq!(receive(&mut ws_stream).await, Response::Status(ServerStatus { paused: false, control: Some(ControlStatus::Free) }));
+
+        assert_eq!(recorded(&log, 1).await, vec![Action::KeySequence { text: "world".to_owned() }]);
+    }
+
+    #[tokio::test]
+    async fn checks_control_before_each_step() {
+        let control = Arc::new(ControlLock::new(ControlPolicy::MostRecent, Duration::from_millis(500)));
+        let (mut ws_stream, log) = start_with(|ctx| ctx.control = control.clone()).await;
+
+        send(&mut ws_stream, json!({ "seq": 1, "sequence": { "steps": [
+            { "action": { "keyDown": { "key": "shift" } } },
+            { "delayMs": 1000, "action": { "keyClick": { "key": "tab" } } },
+        ] } })).await;
+        assert_eq!(receive(&mut ws_stream).await, Response::Ack { seq: 1 });
+        assert_eq!(recorded(&log, 1).await, vec![Action::KeyDown { key: Key::Shift }]);
+
+        // Another client takes over while the sequence waits
+        sleep(Duration::from_millis(600)).await;
+        assert!(matches!(control.acquire(ClientId::next(), false), Acquire::TakenFrom(_)));
+
+        sleep(Duration::from_millis(800)).await;
+        assert_eq!(*log.lock().unwrap(), vec![Action::KeyDown { key: Key::Shift }]);
+    }
+
+    #[tokio::test]
+    async fn limits_pairing_attempts() {
+        let (mut ws_stream, _) = start_with(|ctx| ctx.security = Arc::new(PairingSecurity::new(Arc::new(DeviceStore::load(None).unwrap())).unwrap())).await;
+
+        let start = json!({ "start": { "publicKey": base64::encode([9u8; 32]) } }).to_string();
+        ws_stream.send(Message::Text(start.clone())).await.unwrap();
+        assert!(matches!(receive_pairing(&mut ws_stream).await, PairingResponse::Challenge { .. }));
+        ws_stream.send(Message::Text(start)).await.unwrap();
+        assert!(matches!(receive_pairing(&mut ws_stream).await, PairingResponse::Failed { .. }));
+
+        for _ in 0..2 {
+            ws_stream.send(Message::Text("{".to_owned())).await.unwrap();
+            assert!(matches!(receive_pairing(&mut ws_stream).await, PairingResponse::Failed { .. }));
+        }
+        let msg = timeout(Duration::from_secs(5), ws_stream.next()).await.unwrap();
+        assert!(matches!(msg, None | Some(Ok(Message::Close(_))) | Some(Err(_))), "Expected close, got {:?}", msg);
+    }
+
+    #[tokio::test]
+    async fn disconnects_revoked_devices() {
+        let devices = Arc::new(DeviceStore::load(None).unwrap());
+        let device = devices.add(None, vec![7; 32]).unwrap();
+        let security = DeviceSecurity::new(devices.clone(), &device.id).unwrap();
+        let (mut ws_stream, _) = start_with(|ctx| ctx.security = Arc::new(PairingSecurity::new(devices.clone()).unwrap())).await;
+
+        let request = json!({ "seq": 1, "keySequence": { "text": "hello" } });
+        ws_stream.send(Message::Binary(security.seal(&serde_json::to_vec(&request).unwrap()).unwrap())).await.unwrap();
+        let msg = timeout(Duration::from_secs(5), ws_stream.next()).await.unwrap().unwrap().unwrap();
+        let response: Response = serde_json::from_slice(&security.open(&msg.into_data()).unwrap()).unwrap();
+        assert_eq!(response, Response::Ack { seq: 1 });
+
+        devices.revoke(&device.id).unwrap();
+        let msg = timeout(Duration::from_secs(5), ws_stream.next()).await.unwrap();
+        assert!(matches!(msg, None | Some(Ok(Message::Close(_))) | Some(Err(_))), "Expected close, got {:?}", msg);
+    }
 }
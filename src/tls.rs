@@ -0,0 +1,85 @@
+use std::{fs, io::BufReader, path::Path, sync::Arc};
+
+use anyhow::{anyhow, Context, Result};
+use ring::digest::{digest, SHA256};
+use rustls_pemfile::Item;
+use tokio_rustls::{rustls::{Certificate, PrivateKey, ServerConfig}, TlsAcceptor};
+use tracing::info;
+
+use crate::{security::write_private, utils::hex};
+
+/// The certificate to terminate TLS with, for serving over `wss://`.
+#[derive(Clone)]
+pub struct TlsConfig {
+    pub acceptor: TlsAcceptor,
+    /// The hex-encoded SHA-256 fingerprint of the certificate, which
+    /// lets clients pin self-signed certificates.
+    pub fingerprint: String,
+}
+
+impl TlsConfig {
+    /// Loads a PEM-encoded certificate (chain) and private key.
+    pub fn load(cert_path: &Path, key_path: &Path) -> Result<Self> {
+        let certs = read_pem(cert_path)?.into_iter()
+            .filter_map(|item| match item {
+                Item::X509Certificate(cert) => Some(cert),
+                _ => None,
+            })
+            .collect();
+        let key = read_pem(key_path)?.into_iter()
+            .find_map(|item| match item {
+                Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(key),
+                _ => None,
+            })
+            .ok_or_else(|| anyhow!("No private key found in {}", key_path.display()))?;
+        Self::new(certs, key)
+    }
+
+    /// Uses the self-signed certificate in the given directory, generating
+    /// (and persisting) one first if there is none. Persisting it keeps the
+    /// fingerprint stable, so clients don't have to pin it again.
+    pub fn self_signed(dir: Option<&Path>) -> Result<Self> {
+        let dir = match dir {
+            Some(dir) => dir,
+            None => {
+                let cert = generate_cert()?;
+                return Self::new(vec![cert.serialize_der()?], cert.serialize_private_key_der());
+            },
+        };
+
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        if !cert_path.exists() || !key_path.exists() {
+            let cert = generate_cert()?;
+            write_private(&key_path, cert.serialize_private_key_pem().as_bytes())
+                .with_context(|| format!("Could not write private key to {}", key_path.display()))?;
+            write_private(&cert_path, cert.serialize_pem()?.as_bytes())
+                .with_context(|| format!("Could not write certificate to {}", cert_path.display()))?;
+            info!("Generated self-signed certificate at {}", cert_path.display());
+        }
+        Self::load(&cert_path, &key_path)
+    }
+
+    fn new(certs: Vec<Vec<u8>>, key: Vec<u8>) -> Result<Self> {
+        let leaf = certs.first().ok_or_else(|| anyhow!("No certificate found"))?;
+        let fingerprint = hex(digest(&SHA256, leaf).as_ref());
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs.into_iter().map(Certificate).collect(), PrivateKey(key))?;
+        Ok(Self { acceptor: TlsAcceptor::from(Arc::new(config)), fingerprint })
+    }
+}
+
+fn read_pem(path: &Path) -> Result<Vec<Item>> {
+    let file = fs::File::open(path).with_context(|| format!("Could not open {}", path.display()))?;
+    let items = rustls_pemfile::read_all(&mut BufReader::new(file)).with_context(|| format!("Could not read {}", path.display()))?;
+    Ok(items)
+}
+
+fn generate_cert() -> Result<rcgen::Certificate> {
+    // Clients pin the fingerprint instead of checking the host name,
+    // since the server's address on the local network may change.
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()])?;
+    Ok(cert)
+}
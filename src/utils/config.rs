@@ -0,0 +1,6 @@
+use std::path::PathBuf;
+
+/// The location of the given file in robo's directory inside the user's config directory.
+pub fn config_path(name: &str) -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("robo").join(name))
+}
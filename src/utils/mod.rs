@@ -1,3 +1,9 @@
+mod config;
+mod hex;
+mod signal;
 mod r#unsafe;
 
+pub use config::*;
+pub use hex::*;
+pub use signal::*;
 pub use r#unsafe::*;